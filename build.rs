@@ -31,7 +31,7 @@ fn main() {
 fn add_const(file_name: &'static str, value: fn() -> String) {
     let out_dir_path = env::var("OUT_DIR").expect("Failed to get OUT_DIR");
     let out_dir = Path::new(&out_dir_path);
-    write_to(&out_dir.clone().join(file_name), &value().trim().as_bytes());
+    write_to(&out_dir.join(file_name), value().trim().as_bytes());
 }
 
 fn write_to(path: &Path, bytes: &[u8]) {
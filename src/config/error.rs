@@ -0,0 +1,88 @@
+use std::{fmt, ops::Range, path::PathBuf};
+
+/// 1-based position in the configuration file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn from_offset(source: &str, offset: usize) -> Self {
+        let offset = offset.min(source.len());
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    pub fn from_span(source: &str, span: Option<Range<usize>>) -> Option<Self> {
+        span.map(|span| Self::from_offset(source, span.start))
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The file is not valid TOML or does not match the expected layout.
+    Syntax {
+        path: PathBuf,
+        location: Option<Location>,
+        message: String,
+    },
+    /// The file parsed but a value is not acceptable.
+    Invalid {
+        path: PathBuf,
+        location: Option<Location>,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    pub fn location(&self) -> Option<Location> {
+        match self {
+            ConfigError::Io { .. } => None,
+            ConfigError::Syntax { location, .. } | ConfigError::Invalid { location, .. } => {
+                *location
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (path, location, message) = match self {
+            ConfigError::Io { path, source } => {
+                return write!(f, "{}: {}", path.display(), source);
+            }
+            ConfigError::Syntax {
+                path,
+                location,
+                message,
+            }
+            | ConfigError::Invalid {
+                path,
+                location,
+                message,
+            } => (path, location, message),
+        };
+        match location {
+            Some(l) => write!(f, "{}:{}:{}: {}", path.display(), l.line, l.column, message),
+            None => write!(f, "{}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
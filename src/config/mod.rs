@@ -0,0 +1,1019 @@
+mod error;
+
+pub use error::*;
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use encoding_rs::Encoding;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{
+    fmt::{builtin_rule, FormatFeature, FormatRule, RegexFlags, RegexRule, StringFormatter},
+    util::dirs,
+    ExpectWithTracing, SERVICE_NAME,
+};
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// File the configuration is read from, it may not exist yet.
+    pub path: Option<PathBuf>,
+    pub pause_on_start: bool,
+    pub log: LogConfig,
+    pub format: FormatConfig,
+    pub selection: SelectionsConfig,
+    pub sync: SyncConfig,
+    /// Named rule sets for [`Config::requestors`].
+    pub profiles: HashMap<String, FormatConfig>,
+    /// Formatting by the app that pastes, the first match wins. Only with [`FormatOn::Paste`].
+    pub requestors: Vec<RequestorConfig>,
+    pub source: SourceConfig,
+}
+
+/// The apps whose copies are handled, by the app that owns the selection.
+#[derive(Debug, Clone, Default)]
+pub struct SourceConfig {
+    /// Only these if not empty.
+    pub include: Vec<AppMatch>,
+    /// Never these, e.g. password managers.
+    pub exclude: Vec<AppMatch>,
+}
+
+impl SourceConfig {
+    /// Whether copies by `app` are handled. An app that can not be told, `None`, may be any of
+    /// the listed ones, so it is only accepted if none are listed.
+    pub fn accepts(&self, app: Option<&App>) -> bool {
+        match app {
+            Some(app) => {
+                (self.include.is_empty() || self.include.iter().any(|m| m.matches(app)))
+                    && !self.exclude.iter().any(|m| m.matches(app))
+            }
+            None => self.include.is_empty() && self.exclude.is_empty(),
+        }
+    }
+}
+
+/// An application as identified by its windows.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct App {
+    /// `WM_CLASS` instance and class names.
+    pub class: Vec<String>,
+    pub exe: Option<PathBuf>,
+}
+
+impl App {
+    /// A name to show, the `WM_CLASS` class name or else the executable name.
+    pub fn name(&self) -> Option<String> {
+        match self.class.last() {
+            Some(class) => Some(class.clone()),
+            None => self
+                .exe
+                .as_ref()
+                .and_then(|e| e.file_name())
+                .map(|n| n.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+/// Matches an [`App`], every given field has to match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppMatch {
+    /// Either `WM_CLASS` name, ignoring case.
+    pub class: Option<String>,
+    /// The executable name, or its full path if it contains a `/`.
+    pub exe: Option<String>,
+}
+
+impl AppMatch {
+    pub fn matches(&self, app: &App) -> bool {
+        let class = match &self.class {
+            Some(class) => app.class.iter().any(|c| c.eq_ignore_ascii_case(class)),
+            None => true,
+        };
+        let exe = match (&self.exe, &app.exe) {
+            (Some(exe), Some(path)) if exe.contains('/') => path == Path::new(exe),
+            (Some(exe), Some(path)) => path.file_name() == Some(exe.as_ref()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        class && exe
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestorConfig {
+    pub app: AppMatch,
+    /// A key of [`Config::profiles`], `None` pastes the text as copied.
+    pub profile: Option<String>,
+}
+
+/// Mirrors text between PRIMARY and CLIPBOARD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncConfig {
+    pub mode: SyncMode,
+    /// PRIMARY must stay unchanged this long, with no mouse button held, before it is mirrored.
+    pub settle: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+    #[default]
+    Off,
+    PrimaryToClipboard,
+    ClipboardToPrimary,
+    Both,
+}
+
+impl SyncMode {
+    /// Selection that text from `from` is mirrored to.
+    pub fn target(&self, from: Selection) -> Option<Selection> {
+        match (self, from) {
+            (SyncMode::PrimaryToClipboard | SyncMode::Both, Selection::Primary) => {
+                Some(Selection::Clipboard)
+            }
+            (SyncMode::ClipboardToPrimary | SyncMode::Both, Selection::Clipboard) => {
+                Some(Selection::Primary)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `selection` is mirrored from or to.
+    pub fn is_synced(&self, selection: Selection) -> bool {
+        self.target(selection).is_some()
+            || Selection::ALL
+                .iter()
+                .any(|s| self.target(*s) == Some(selection))
+    }
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            mode: SyncMode::Off,
+            settle: Duration::from_millis(300),
+        }
+    }
+}
+
+/// X11 selections, other platforms only have [`Selection::Clipboard`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Selection {
+    Clipboard,
+    Primary,
+    Secondary,
+}
+
+impl Selection {
+    pub const ALL: [Selection; 3] = [
+        Selection::Clipboard,
+        Selection::Primary,
+        Selection::Secondary,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Selection::Clipboard => "clipboard",
+            Selection::Primary => "primary",
+            Selection::Secondary => "secondary",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SelectionsConfig {
+    pub clipboard: SelectionConfig,
+    pub primary: SelectionConfig,
+    pub secondary: SelectionConfig,
+    /// Encoding of `STRING` data, Latin-1 as ICCCM says if `None`. Some apps and input methods
+    /// put their locale encoding there, e.g. GBK.
+    pub string_encoding: Option<&'static Encoding>,
+    pub format_on: FormatOn,
+    /// Changes this close to each other are read once, after the last one. Some apps take a
+    /// selection over several times per copy.
+    pub debounce: Duration,
+}
+
+/// When the text of a copy is formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FormatOn {
+    /// Right away, the selection is rewritten with the formatted text.
+    #[default]
+    Copy,
+    /// Whenever it is pasted, the copied text stays available as is.
+    Paste,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelectionConfig {
+    pub enabled: bool,
+    /// Replaces `[format] rules` for this selection.
+    pub format: Option<FormatConfig>,
+    /// Keeps the text and the other targets of a copy once its app exits.
+    pub persist: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogConfig {
+    pub level: Option<log::Level>,
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FormatConfig {
+    pub rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub enum RuleConfig {
+    Builtin(String),
+    Regex(RegexRule),
+}
+
+impl RuleConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            RuleConfig::Builtin(name) => name,
+            RuleConfig::Regex(rule) => rule.name(),
+        }
+    }
+
+    fn build(&self) -> Box<dyn FormatRule> {
+        match self {
+            RuleConfig::Builtin(name) => builtin_rule(name).expectx(name),
+            RuleConfig::Regex(rule) => Box::new(rule.clone()),
+        }
+    }
+}
+
+impl FormatConfig {
+    pub fn formatter(&self) -> StringFormatter {
+        StringFormatter::from_rules(self.rules.iter().map(RuleConfig::build).collect())
+    }
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        let rules = FormatFeature::default()
+            .rules()
+            .iter()
+            .map(|r| RuleConfig::Builtin(r.name().to_owned()))
+            .collect();
+        Self { rules }
+    }
+}
+
+impl SelectionsConfig {
+    pub fn get(&self, selection: Selection) -> &SelectionConfig {
+        match selection {
+            Selection::Clipboard => &self.clipboard,
+            Selection::Primary => &self.primary,
+            Selection::Secondary => &self.secondary,
+        }
+    }
+}
+
+impl Default for SelectionsConfig {
+    fn default() -> Self {
+        let disabled = SelectionConfig {
+            enabled: false,
+            format: None,
+            persist: false,
+        };
+        Self {
+            clipboard: SelectionConfig {
+                enabled: true,
+                ..disabled.clone()
+            },
+            primary: disabled.clone(),
+            secondary: disabled,
+            string_encoding: None,
+            format_on: FormatOn::Copy,
+            debounce: Duration::from_millis(50),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: Self::default_path(),
+            pause_on_start: false,
+            log: Default::default(),
+            format: Default::default(),
+            selection: Default::default(),
+            sync: Default::default(),
+            profiles: Default::default(),
+            requestors: Default::default(),
+            source: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/clipd/config.toml`, `%APPDATA%\clipd\config.toml` on Windows.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_home().map(|d| d.join(SERVICE_NAME).join(CONFIG_FILE_NAME))
+    }
+
+    /// Loads `path` if given, otherwise the default path, falling back to the built-in
+    /// defaults when the default file does not exist.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        match path {
+            // Kept absolute, the daemon changes its working directory.
+            Some(path) if path.is_relative() => Self::from_file(
+                &std::env::current_dir()
+                    .map_err(|e| ConfigError::Io {
+                        path: path.to_owned(),
+                        source: e,
+                    })?
+                    .join(path),
+            ),
+            Some(path) => Self::from_file(path),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => Self::from_file(&path),
+                _ => Ok(Self::default()),
+            },
+        }
+    }
+
+    /// Reads the file again, the built-in defaults are used if it has been removed.
+    pub fn reload(&self) -> Result<Self, ConfigError> {
+        match &self.path {
+            Some(path) if path.exists() => Self::from_file(path),
+            _ => Ok(Self {
+                path: self.path.clone(),
+                ..Self::default()
+            }),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let source = std::fs::read_to_string(path).map_err(|e| ConfigError::Io {
+            path: path.to_owned(),
+            source: e,
+        })?;
+        Self::parse(path, &source)
+    }
+
+    pub fn parse(path: &Path, source: &str) -> Result<Self, ConfigError> {
+        let raw: RawConfig = toml::from_str(source).map_err(|e| ConfigError::Syntax {
+            path: path.to_owned(),
+            location: Location::from_span(source, e.span()),
+            message: e.message().to_owned(),
+        })?;
+        Validator { path, source }.validate(raw)
+    }
+
+    pub fn formatter(&self) -> StringFormatter {
+        self.format.formatter()
+    }
+
+    /// The app matchers of [`Config::requestors`] with their formatter, `None` if the text is
+    /// pasted as copied.
+    pub fn requestor_formatters(&self) -> Vec<(AppMatch, Option<StringFormatter>)> {
+        self.requestors
+            .iter()
+            .map(|r| {
+                let formatter = r.profile.as_ref().map(|p| self.profiles[p].formatter());
+                (r.app.clone(), formatter)
+            })
+            .collect()
+    }
+
+    /// Formatters of the enabled selections.
+    pub fn selection_formatters(&self) -> HashMap<Selection, StringFormatter> {
+        Selection::ALL
+            .into_iter()
+            .filter(|s| self.selection.get(*s).enabled)
+            .map(|s| {
+                let format = self.selection.get(s).format.as_ref();
+                (s, format.unwrap_or(&self.format).formatter())
+            })
+            .collect()
+    }
+}
+
+// region: Raw
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawConfig {
+    pause_on_start: bool,
+    log: RawLogConfig,
+    format: RawFormatConfig,
+    selection: RawSelectionsConfig,
+    sync: RawSyncConfig,
+    profile: HashMap<String, RawProfileConfig>,
+    requestor: Vec<Spanned<RawRequestorConfig>>,
+    source: RawSourceConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawLogConfig {
+    level: Option<Spanned<String>>,
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawFormatConfig {
+    rules: Option<RawRules>,
+    regex: Vec<RawRegexRule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawSelectionsConfig {
+    clipboard: RawSelectionConfig,
+    primary: RawSelectionConfig,
+    secondary: RawSelectionConfig,
+    string_encoding: Option<Spanned<String>>,
+    format_on: FormatOn,
+    debounce_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawSelectionConfig {
+    enabled: Option<bool>,
+    rules: Option<RawRules>,
+    persist: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawSyncConfig {
+    mode: SyncMode,
+    settle_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawProfileConfig {
+    rules: RawRules,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRequestorConfig {
+    #[serde(default)]
+    class: Option<String>,
+    #[serde(default)]
+    exe: Option<String>,
+    #[serde(default)]
+    profile: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawSourceConfig {
+    include: Vec<Spanned<RawAppMatch>>,
+    exclude: Vec<Spanned<RawAppMatch>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAppMatch {
+    #[serde(default)]
+    class: Option<String>,
+    #[serde(default)]
+    exe: Option<String>,
+}
+
+type RawRules = Spanned<Vec<Spanned<String>>>;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRegexRule {
+    name: Spanned<String>,
+    pattern: Spanned<String>,
+    #[serde(default)]
+    replace: String,
+    #[serde(default)]
+    flags: Option<Spanned<String>>,
+    #[serde(default)]
+    whole_match: bool,
+}
+// endregion: Raw
+
+struct Validator<'a> {
+    path: &'a Path,
+    source: &'a str,
+}
+
+impl Validator<'_> {
+    fn invalid<S: Into<String>>(&self, span: Range<usize>, message: S) -> ConfigError {
+        ConfigError::Invalid {
+            path: self.path.to_owned(),
+            location: Location::from_span(self.source, Some(span)),
+            message: message.into(),
+        }
+    }
+
+    fn validate(&self, raw: RawConfig) -> Result<Config, ConfigError> {
+        let regex_rules = self.regex_rules(raw.format.regex)?;
+        let selection = |raw: RawSelectionConfig, default: SelectionConfig| {
+            Ok::<_, ConfigError>(SelectionConfig {
+                enabled: raw.enabled.unwrap_or(default.enabled),
+                format: match raw.rules {
+                    Some(rules) => Some(self.rules(rules, &regex_rules)?),
+                    None => None,
+                },
+                persist: raw.persist,
+            })
+        };
+        let defaults = SelectionsConfig::default();
+        let profiles = raw
+            .profile
+            .into_iter()
+            .map(|(name, profile)| Ok((name, self.rules(profile.rules, &regex_rules)?)))
+            .collect::<Result<HashMap<_, _>, ConfigError>>()?;
+        let requestors = raw
+            .requestor
+            .into_iter()
+            .map(|raw| self.requestor(raw, &profiles))
+            .collect::<Result<_, _>>()?;
+        Ok(Config {
+            path: Some(self.path.to_owned()),
+            pause_on_start: raw.pause_on_start,
+            log: self.log(raw.log)?,
+            selection: SelectionsConfig {
+                clipboard: selection(raw.selection.clipboard, defaults.clipboard)?,
+                primary: selection(raw.selection.primary, defaults.primary)?,
+                secondary: selection(raw.selection.secondary, defaults.secondary)?,
+                string_encoding: match raw.selection.string_encoding {
+                    Some(label) => Some(
+                        Encoding::for_label(label.get_ref().as_bytes())
+                            .ok_or_else(|| self.invalid(label.span(), "unknown encoding"))?,
+                    ),
+                    None => None,
+                },
+                format_on: raw.selection.format_on,
+                debounce: raw
+                    .selection
+                    .debounce_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.debounce),
+            },
+            sync: SyncConfig {
+                mode: raw.sync.mode,
+                settle: raw
+                    .sync
+                    .settle_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(SyncConfig::default().settle),
+            },
+            profiles,
+            requestors,
+            source: SourceConfig {
+                include: self.app_matches(raw.source.include)?,
+                exclude: self.app_matches(raw.source.exclude)?,
+            },
+            format: match raw.format.rules {
+                Some(rules) => self.rules(rules, &regex_rules)?,
+                None => {
+                    let mut format = FormatConfig::default();
+                    format
+                        .rules
+                        .extend(regex_rules.into_iter().map(RuleConfig::Regex));
+                    format
+                }
+            },
+        })
+    }
+
+    fn log(&self, raw: RawLogConfig) -> Result<LogConfig, ConfigError> {
+        let level = match raw.level {
+            Some(level) => Some(
+                log::Level::from_str(level.get_ref())
+                    .map_err(|_| self.invalid(level.span(), "unknown log level"))?,
+            ),
+            None => None,
+        };
+        Ok(LogConfig {
+            level,
+            dir: raw.dir,
+        })
+    }
+
+    fn regex_rules(&self, raw: Vec<RawRegexRule>) -> Result<Vec<RegexRule>, ConfigError> {
+        let mut regex_rules = vec![];
+        let mut names = HashSet::new();
+        for rule in raw {
+            let name = rule.name.get_ref();
+            if builtin_rule(name).is_some() || !names.insert(name.clone()) {
+                return Err(self.invalid(rule.name.span(), "duplicate rule name"));
+            }
+            let flags = match &rule.flags {
+                Some(flags) => RegexFlags::from_str(flags.get_ref())
+                    .map_err(|e| self.invalid(flags.span(), e.to_string()))?,
+                None => RegexFlags::empty(),
+            };
+            let mut regex = RegexRule::new(name, rule.pattern.get_ref(), &rule.replace, flags)
+                .map_err(|e| self.invalid(rule.pattern.span(), format!("{:#}", e)))?;
+            if rule.whole_match {
+                regex = regex.whole_match();
+            }
+            regex_rules.push(regex);
+        }
+        Ok(regex_rules)
+    }
+
+    fn requestor(
+        &self,
+        raw: Spanned<RawRequestorConfig>,
+        profiles: &HashMap<String, FormatConfig>,
+    ) -> Result<RequestorConfig, ConfigError> {
+        let span = raw.span();
+        let raw = raw.into_inner();
+        let app = self.app_match(span, raw.class, raw.exe)?;
+        let profile = match raw.profile {
+            Some(p) if !profiles.contains_key(p.get_ref()) => {
+                return Err(self.invalid(p.span(), "unknown profile"))
+            }
+            p => p.map(Spanned::into_inner),
+        };
+        Ok(RequestorConfig { app, profile })
+    }
+
+    fn app_matches(&self, raw: Vec<Spanned<RawAppMatch>>) -> Result<Vec<AppMatch>, ConfigError> {
+        raw.into_iter()
+            .map(|raw| {
+                let span = raw.span();
+                let raw = raw.into_inner();
+                self.app_match(span, raw.class, raw.exe)
+            })
+            .collect()
+    }
+
+    fn app_match(
+        &self,
+        span: Range<usize>,
+        class: Option<String>,
+        exe: Option<String>,
+    ) -> Result<AppMatch, ConfigError> {
+        if class.is_none() && exe.is_none() {
+            return Err(self.invalid(span, "class or exe is required"));
+        }
+        Ok(AppMatch { class, exe })
+    }
+
+    /// Resolves rule names against the built-in and the `[[format.regex]]` rules.
+    fn rules(
+        &self,
+        rules: RawRules,
+        regex_rules: &[RegexRule],
+    ) -> Result<FormatConfig, ConfigError> {
+        if rules.get_ref().is_empty() {
+            return Err(self.invalid(rules.span(), "no rule enabled"));
+        }
+        rules
+            .into_inner()
+            .into_iter()
+            .map(|name| {
+                let rule = if builtin_rule(name.get_ref()).is_some() {
+                    RuleConfig::Builtin(name.get_ref().clone())
+                } else {
+                    let regex = regex_rules.iter().find(|r| r.name() == name.get_ref());
+                    match regex {
+                        Some(regex) => RuleConfig::Regex(regex.clone()),
+                        None => return Err(self.invalid(name.span(), "unknown rule")),
+                    }
+                };
+                Ok(rule)
+            })
+            .collect::<Result<_, _>>()
+            .map(|rules| FormatConfig { rules })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use std::time::Duration;
+
+    use super::{App, AppMatch, Config, ConfigError, FormatOn, Location, Selection, SyncMode};
+    use crate::fmt::Formatter;
+
+    fn parse(source: &str) -> Result<Config, ConfigError> {
+        Config::parse(Path::new("config.toml"), source)
+    }
+
+    fn location(source: &str) -> Location {
+        parse(source).unwrap_err().location().unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = parse("").unwrap();
+        assert!(!config.pause_on_start);
+        assert_eq!(config.log.level, None);
+        assert_eq!(
+            config.formatter().rules().collect::<Vec<_>>(),
+            ["trim-cr", "trim-start-whitespace", "trim-end-whitespace"]
+        );
+    }
+
+    #[test]
+    fn full() {
+        let config = parse(
+            r#"
+pause_on_start = true
+
+[log]
+level = "debug"
+dir = "/var/log"
+
+[format]
+rules = ["ticket", "trim-end-lf"]
+
+[[format.regex]]
+name = "ticket"
+pattern = '^[a-z]+-\d+: '
+flags = "im"
+
+[[format.regex]]
+name = "unused"
+pattern = "x"
+"#,
+        )
+        .unwrap();
+        assert!(config.pause_on_start);
+        assert_eq!(config.log.level, Some(log::Level::Debug));
+        assert_eq!(config.log.dir.as_deref(), Some(Path::new("/var/log")));
+
+        let formatter = config.formatter();
+        assert_eq!(
+            formatter.rules().collect::<Vec<_>>(),
+            ["ticket", "trim-end-lf"]
+        );
+        let fmt_result = formatter.fmt_unckecked(&"ab-1: x\nCD-2: y\n".to_string());
+        assert_eq!(fmt_result.data, "x\ny");
+    }
+
+    #[test]
+    fn selections() {
+        let formatters = parse("").unwrap().selection_formatters();
+        assert_eq!(
+            formatters.keys().collect::<Vec<_>>(),
+            [&Selection::Clipboard]
+        );
+
+        let config = parse(
+            r#"
+[format]
+rules = ["trim-cr"]
+
+[selection.clipboard]
+enabled = false
+
+[selection.primary]
+enabled = true
+rules = ["trim-end-lf", "x"]
+
+[selection.secondary]
+enabled = true
+persist = true
+
+[[format.regex]]
+name = "x"
+pattern = "x"
+"#,
+        )
+        .unwrap();
+        assert!(!config.selection.clipboard.persist);
+        assert!(config.selection.secondary.persist);
+        let formatters = config.selection_formatters();
+        assert!(!formatters.contains_key(&Selection::Clipboard));
+        assert_eq!(
+            formatters[&Selection::Primary].rules().collect::<Vec<_>>(),
+            ["trim-end-lf", "x"]
+        );
+        assert_eq!(
+            formatters[&Selection::Secondary]
+                .rules()
+                .collect::<Vec<_>>(),
+            ["trim-cr"]
+        );
+
+        assert_eq!(
+            location("[selection.primary]\nrules = [\"nope\"]"),
+            Location {
+                line: 2,
+                column: 10
+            }
+        );
+        assert!(parse("[selection.tertiary]").is_err());
+
+        assert_eq!(parse("").unwrap().selection.string_encoding, None);
+        assert_eq!(parse("").unwrap().selection.format_on, FormatOn::Copy);
+        assert_eq!(
+            parse("").unwrap().selection.debounce,
+            Duration::from_millis(50)
+        );
+        let config = parse("[selection]\ndebounce_ms = 0").unwrap();
+        assert_eq!(config.selection.debounce, Duration::ZERO);
+        let config = parse("[selection]\nformat_on = \"paste\"").unwrap();
+        assert_eq!(config.selection.format_on, FormatOn::Paste);
+        let config = parse("[selection]\nstring_encoding = \"gb18030\"").unwrap();
+        assert_eq!(config.selection.string_encoding, Some(encoding_rs::GB18030));
+        assert_eq!(
+            location("[selection]\nstring_encoding = \"klingon\""),
+            Location {
+                line: 2,
+                column: 19
+            }
+        );
+    }
+
+    #[test]
+    fn sync() {
+        let config = parse("").unwrap();
+        assert_eq!(config.sync.mode, SyncMode::Off);
+        assert_eq!(config.sync.mode.target(Selection::Primary), None);
+
+        let config = parse("[sync]\nmode = \"primary-to-clipboard\"\nsettle_ms = 50").unwrap();
+        assert!(config.sync.mode.is_synced(Selection::Clipboard));
+        assert!(!config.sync.mode.is_synced(Selection::Secondary));
+        assert_eq!(config.sync.settle, Duration::from_millis(50));
+        assert_eq!(
+            config.sync.mode.target(Selection::Primary),
+            Some(Selection::Clipboard)
+        );
+        assert_eq!(config.sync.mode.target(Selection::Clipboard), None);
+        assert_eq!(
+            SyncMode::Both.target(Selection::Clipboard),
+            Some(Selection::Primary)
+        );
+        assert_eq!(SyncMode::Both.target(Selection::Secondary), None);
+
+        assert_eq!(
+            location("[sync]\nmode = \"sideways\""),
+            Location { line: 2, column: 8 }
+        );
+    }
+
+    #[test]
+    fn requestors() {
+        let config = parse(
+            r#"
+[profile.terminal]
+rules = ["trim-end-whitespace"]
+
+[[requestor]]
+class = "kitty"
+profile = "terminal"
+
+[[requestor]]
+exe = "/usr/bin/keepassxc"
+"#,
+        )
+        .unwrap();
+        let formatters = config.requestor_formatters();
+        let app = |class: &str, exe: &str| App {
+            class: vec![class.into()],
+            exe: Some(exe.into()),
+        };
+        let (matcher, formatter) = &formatters[0];
+        assert!(matcher.matches(&app("Kitty", "/usr/bin/kitty")));
+        assert_eq!(
+            formatter.as_ref().unwrap().rules().collect::<Vec<_>>(),
+            ["trim-end-whitespace"]
+        );
+        let (matcher, formatter) = &formatters[1];
+        assert!(matcher.matches(&app("x", "/usr/bin/keepassxc")));
+        assert!(!matcher.matches(&app("x", "/opt/keepassxc")));
+        assert!(!matcher.matches(&App::default()));
+        assert!(formatter.is_none());
+
+        let exe = AppMatch {
+            class: None,
+            exe: Some("kitty".into()),
+        };
+        assert!(exe.matches(&app("", "/usr/bin/kitty")));
+
+        assert_eq!(
+            location("[[requestor]]\nclass = \"x\"\nprofile = \"nope\""),
+            Location {
+                line: 3,
+                column: 11
+            }
+        );
+        assert!(parse("[[requestor]]\nprofile = \"x\"").is_err());
+        assert!(parse("[profile.x]\nrules = []").is_err());
+    }
+
+    #[test]
+    fn source() {
+        let app = |class: &str| App {
+            class: vec![class.into()],
+            exe: None,
+        };
+        assert!(parse("").unwrap().source.accepts(Some(&App::default())));
+        assert!(parse("").unwrap().source.accepts(None));
+
+        let config = parse(
+            r#"
+[source]
+exclude = [{ class = "KeePassXC" }, { exe = "1password" }]
+"#,
+        )
+        .unwrap();
+        assert!(!config.source.accepts(Some(&app("keepassxc"))));
+        assert!(config.source.accepts(Some(&app("firefox"))));
+        assert!(!config.source.accepts(None));
+
+        let config = parse(
+            r#"
+[source]
+include = [{ class = "firefox" }, { class = "Evince" }]
+exclude = [{ class = "evince" }]
+"#,
+        )
+        .unwrap();
+        assert!(config.source.accepts(Some(&app("Firefox"))));
+        assert!(!config.source.accepts(Some(&app("evince"))));
+        assert!(!config.source.accepts(Some(&App::default())));
+        assert!(!config.source.accepts(None));
+
+        assert_eq!(
+            location("[source]\ninclude = [{}]"),
+            Location {
+                line: 2,
+                column: 12
+            }
+        );
+    }
+
+    #[test]
+    fn regex_rules_enabled_by_default() {
+        let config = parse("[[format.regex]]\nname = \"x\"\npattern = \"x\"").unwrap();
+        assert_eq!(config.formatter().rules().last(), Some("x"),);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            parse("pause_on_start = 1"),
+            Err(ConfigError::Syntax { .. })
+        ));
+        assert_eq!(
+            location("\n\npause_on_start = 1"),
+            Location {
+                line: 3,
+                column: 18
+            }
+        );
+        assert_eq!(location("[log]\nfoo = 1"), Location { line: 2, column: 1 });
+        assert_eq!(
+            location("[log]\nlevel = \"loud\""),
+            Location { line: 2, column: 9 }
+        );
+        assert_eq!(
+            location("[format]\nrules = [\"trim-cr\", \"nope\"]"),
+            Location {
+                line: 2,
+                column: 21
+            }
+        );
+        assert_eq!(
+            location("[format]\nrules = []"),
+            Location { line: 2, column: 9 }
+        );
+        assert_eq!(
+            location("[[format.regex]]\nname = \"x\"\npattern = \"(\""),
+            Location {
+                line: 3,
+                column: 11
+            }
+        );
+        assert_eq!(
+            location("[[format.regex]]\nname = \"x\"\npattern = \"x\"\nflags = \"q\""),
+            Location { line: 4, column: 9 }
+        );
+        assert_eq!(
+            location("[[format.regex]]\nname = \"trim-cr\"\npattern = \"x\""),
+            Location { line: 2, column: 8 }
+        );
+        assert!(matches!(
+            parse("[log]\nlevel = \"loud\""),
+            Err(ConfigError::Invalid { .. })
+        ));
+        assert_eq!(
+            parse("[log]\nlevel = \"loud\"").unwrap_err().to_string(),
+            "config.toml:2:9: unknown log level"
+        );
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
 #[derive(Parser, Debug)]
@@ -9,6 +11,10 @@ pub struct Args {
     /// Print version information
     #[arg(short = 'V', long)]
     pub version: bool,
+
+    /// Configuration file [default: $XDG_CONFIG_HOME/clipd/config.toml]
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
 }
 
 #[derive(clap::Subcommand, Debug)]
@@ -28,7 +34,9 @@ pub enum SubCommand {
     /// Restart service
     Restart,
     /// Query service status
-    Status,
+    Status(StatusArgs),
+    /// Reload configuration
+    Reload,
     /// Uninstall service
     Uninstall(UninstallArgs),
 }
@@ -40,6 +48,13 @@ pub struct RunArgs {
     pub daemon: bool,
 }
 
+#[derive(clap::Parser, Debug)]
+pub struct StatusArgs {
+    /// Print status as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
 #[cfg(target_os = "windows")]
 #[derive(clap::Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
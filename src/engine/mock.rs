@@ -0,0 +1,389 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+use anyhow::{bail, Result};
+
+use super::{ChangeFilter, ClipboardBackend, WaitingBackend};
+use crate::config::{App, Selection};
+
+/// Owner of everything the engine writes.
+pub const CLIPD: &str = "clipd";
+
+#[derive(Debug)]
+enum Step {
+    /// `owner` put `text` into a selection, `None` if it is not text.
+    Copy {
+        selection: Selection,
+        owner: String,
+        text: Option<String>,
+    },
+    /// Like `Copy`, but right after the next read, before anything is written back.
+    CopyAfterRead {
+        selection: Selection,
+        owner: String,
+        text: String,
+    },
+    /// Like `Copy`, but while the next write is prepared, after the engine checked that nothing
+    /// changed since the read.
+    CopyDuringWrite {
+        selection: Selection,
+        owner: String,
+        text: String,
+    },
+    /// Nothing changes until the deadline of the wait.
+    Quiet,
+    /// Waiting failed, like a lost connection.
+    Fail(String),
+}
+
+/// Renders deferred text for the app that pastes.
+type Render = Box<dyn Fn(&App) -> String + Send + Sync>;
+
+/// A clipboard in memory that replays a script of copies, for driving the engine without a
+/// display. Owners are apps of that `WM_CLASS`, the empty name is an app that can not be told.
+pub struct MockBackend {
+    script: VecDeque<Step>,
+    /// Changes not reported yet, before the script goes on.
+    changes: VecDeque<Selection>,
+    /// Owner and text of each selection.
+    contents: HashMap<Selection, (String, Option<String>)>,
+    /// Counts the changes of each selection.
+    versions: HashMap<Selection, u64>,
+    /// Version of each selection when it was last read.
+    read_versions: HashMap<Selection, u64>,
+    /// Owner of each selection when it was last read.
+    read_owners: HashMap<Selection, String>,
+    writes: Vec<(Selection, String)>,
+    /// How each selection we own with deferred text renders it.
+    deferred: HashMap<Selection, Render>,
+    saved: HashMap<Selection, String>,
+    filter: Option<Box<dyn ChangeFilter>>,
+    /// How many more times the user is still selecting.
+    selecting: Cell<usize>,
+    reads: usize,
+    failing_reads: usize,
+    failing_writes: usize,
+    echo: bool,
+    versioned: bool,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self {
+            script: VecDeque::new(),
+            changes: VecDeque::new(),
+            contents: HashMap::new(),
+            versions: HashMap::new(),
+            read_versions: HashMap::new(),
+            read_owners: HashMap::new(),
+            writes: vec![],
+            deferred: HashMap::new(),
+            saved: HashMap::new(),
+            filter: None,
+            selecting: Cell::new(0),
+            reads: 0,
+            failing_reads: 0,
+            failing_writes: 0,
+            echo: true,
+            versioned: true,
+        }
+    }
+
+    /// `owner` copies `text` into `selection`.
+    pub fn copy(mut self, selection: Selection, owner: &str, text: &str) -> Self {
+        self.script.push_back(Step::Copy {
+            selection,
+            owner: owner.to_owned(),
+            text: Some(text.to_owned()),
+        });
+        self
+    }
+
+    /// `owner` copies something that is not text, like an image.
+    pub fn copy_other(mut self, selection: Selection, owner: &str) -> Self {
+        self.script.push_back(Step::Copy {
+            selection,
+            owner: owner.to_owned(),
+            text: None,
+        });
+        self
+    }
+
+    /// `owner` copies `text` into `selection` while the engine formats what it read.
+    pub fn copy_after_read(mut self, selection: Selection, owner: &str, text: &str) -> Self {
+        self.script.push_back(Step::CopyAfterRead {
+            selection,
+            owner: owner.to_owned(),
+            text: text.to_owned(),
+        });
+        self
+    }
+
+    /// `owner` copies `text` into `selection` while the engine writes, so the write is dropped.
+    pub fn copy_during_write(mut self, selection: Selection, owner: &str, text: &str) -> Self {
+        self.script.push_back(Step::CopyDuringWrite {
+            selection,
+            owner: owner.to_owned(),
+            text: text.to_owned(),
+        });
+        self
+    }
+
+    /// A wait with a deadline ends without a change, so that what was copied before is
+    /// debounced apart from what follows.
+    pub fn quiet(mut self) -> Self {
+        self.script.push_back(Step::Quiet);
+        self
+    }
+
+    /// Waiting for the next change fails with `message`.
+    pub fn fail(mut self, message: &str) -> Self {
+        self.script.push_back(Step::Fail(message.to_owned()));
+        self
+    }
+
+    /// The next `count` reads fail.
+    pub fn fail_reads(mut self, count: usize) -> Self {
+        self.failing_reads = count;
+        self
+    }
+
+    /// The next `count` writes fail.
+    pub fn fail_writes(mut self, count: usize) -> Self {
+        self.failing_writes = count;
+        self
+    }
+
+    /// Our own writes are not reported as changes, like X11 owner filtering.
+    pub fn without_echo(mut self) -> Self {
+        self.echo = false;
+        self
+    }
+
+    /// Whether changes can not be told apart by version, so that the engine compares texts.
+    pub fn without_versions(mut self) -> Self {
+        self.versioned = false;
+        self
+    }
+
+    /// Only changes `filter` wants are reported, and read if it accepts their owner.
+    pub fn with_filter(mut self, filter: Box<dyn ChangeFilter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// The user is still selecting the next `count` times it is asked.
+    pub fn selecting(mut self, count: usize) -> Self {
+        self.selecting = Cell::new(count);
+        self
+    }
+
+    /// Whether every scripted step and change was reported.
+    pub fn is_done(&self) -> bool {
+        self.script.is_empty() && self.changes.is_empty()
+    }
+
+    /// Everything written, in order.
+    pub fn writes(&self) -> &[(Selection, String)] {
+        &self.writes
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads
+    }
+
+    pub fn owner(&self, selection: Selection) -> Option<&str> {
+        self.contents
+            .get(&selection)
+            .map(|(owner, _)| owner.as_str())
+    }
+
+    pub fn text(&self, selection: Selection) -> Option<&str> {
+        self.contents.get(&selection)?.1.as_deref()
+    }
+
+    /// What `requestor` gets when it pastes `selection`.
+    pub fn paste(&self, selection: Selection, requestor: &str) -> Option<String> {
+        match self.deferred.get(&selection) {
+            Some(render) => Some(render(&app(requestor))),
+            None => self.text(selection).map(str::to_owned),
+        }
+    }
+
+    /// What was kept of `selection` for once its owner is gone.
+    pub fn saved(&self, selection: Selection) -> Option<&str> {
+        self.saved.get(&selection).map(String::as_str)
+    }
+
+    fn set(&mut self, selection: Selection, owner: String, text: Option<String>) {
+        self.contents.insert(selection, (owner, text));
+        *self.versions.entry(selection).or_default() += 1;
+        self.deferred.remove(&selection);
+    }
+
+    /// Writes `text` as [`CLIPD`], `false` if the script copies to `selection` first.
+    fn write(&mut self, selection: Selection, text: String) -> Result<bool> {
+        if self.failing_writes > 0 {
+            self.failing_writes -= 1;
+            bail!("Write {} failed", selection.name())
+        }
+        match self.script.pop_front() {
+            Some(Step::CopyDuringWrite {
+                selection,
+                owner,
+                text,
+            }) => {
+                self.set(selection, owner, Some(text));
+                self.changes.push_back(selection);
+                return Ok(false);
+            }
+            Some(step) => self.script.push_front(step),
+            None => {}
+        }
+        self.writes.push((selection, text.clone()));
+        self.set(selection, CLIPD.to_owned(), Some(text));
+        if self.echo {
+            self.changes.push_back(selection);
+        }
+        Ok(true)
+    }
+
+    /// The next change, wanted or not.
+    fn next_change(&mut self, deadline: Option<Instant>) -> Result<Option<Selection>> {
+        if let Some(selection) = self.changes.pop_front() {
+            return Ok(Some(selection));
+        }
+        if let Some(Step::Quiet) = self.script.front() {
+            self.script.pop_front();
+            if deadline.is_some() {
+                return Ok(None);
+            }
+        }
+        match self.script.pop_front() {
+            Some(Step::Copy {
+                selection,
+                owner,
+                text,
+            }) => {
+                self.set(selection, owner, text);
+                Ok(Some(selection))
+            }
+            Some(Step::CopyAfterRead { selection, .. }) => {
+                bail!("Nothing read before copying to {}", selection.name())
+            }
+            Some(Step::CopyDuringWrite { selection, .. }) => {
+                bail!("Nothing written before copying to {}", selection.name())
+            }
+            Some(Step::Quiet) => Ok(None),
+            Some(Step::Fail(message)) => bail!("{}", message),
+            None => Ok(None),
+        }
+    }
+}
+
+fn app(owner: &str) -> App {
+    App {
+        class: vec![owner.to_owned()],
+        exe: None,
+    }
+}
+
+impl ClipboardBackend for MockBackend {
+    fn read_text(&mut self, selection: Selection) -> Result<Option<String>> {
+        self.reads += 1;
+        if self.failing_reads > 0 {
+            self.failing_reads -= 1;
+            bail!("Read {} failed", selection.name())
+        }
+        let owner = self.owner(selection).filter(|o| !o.is_empty()).map(app);
+        if let Some(filter) = &self.filter {
+            if !filter.accepts(selection, owner.as_ref()) {
+                self.read_versions.remove(&selection);
+                self.read_owners.remove(&selection);
+                return Ok(None);
+            }
+        }
+        let text = self.text(selection).map(str::to_owned);
+        let version = self.versions.get(&selection).copied().unwrap_or_default();
+        self.read_versions.insert(selection, version);
+        if let Some(owner) = self.owner(selection).map(str::to_owned) {
+            self.read_owners.insert(selection, owner);
+        }
+        match self.script.pop_front() {
+            Some(Step::CopyAfterRead {
+                selection,
+                owner,
+                text,
+            }) => {
+                self.set(selection, owner, Some(text));
+                self.changes.push_back(selection);
+            }
+            Some(step) => self.script.push_front(step),
+            None => {}
+        }
+        Ok(text)
+    }
+
+    fn write_text(&mut self, selection: Selection, text: String) -> Result<bool> {
+        self.write(selection, text)
+    }
+
+    fn echoes_writes(&self) -> bool {
+        self.echo
+    }
+
+    fn read_owner(&self, selection: Selection) -> Option<String> {
+        self.read_owners.get(&selection).cloned()
+    }
+
+    fn is_unchanged(&mut self, selection: Selection) -> Result<Option<bool>> {
+        if !self.versioned {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.read_versions.get(&selection) == self.versions.get(&selection),
+        ))
+    }
+
+    fn defer_text<F>(&mut self, selection: Selection, text: String, format: F) -> Result<bool>
+    where
+        F: Fn(&App) -> String + Send + Sync + 'static,
+    {
+        if self.is_unchanged(selection)? == Some(false) || !self.write(selection, text)? {
+            return Ok(false);
+        }
+        self.deferred.insert(selection, Box::new(format));
+        Ok(true)
+    }
+
+    fn save(&mut self, selection: Selection, text: &str) -> Result<()> {
+        self.saved.insert(selection, text.to_owned());
+        Ok(())
+    }
+
+    fn store_text(&mut self, selection: Selection, text: String) -> Result<()> {
+        self.write(selection, text)?;
+        Ok(())
+    }
+
+    fn is_selecting(&self) -> Result<bool> {
+        let selecting = self.selecting.get();
+        self.selecting.set(selecting.saturating_sub(1));
+        Ok(selecting > 0)
+    }
+}
+
+impl WaitingBackend for MockBackend {
+    fn wait_for_change(&mut self, deadline: Option<Instant>) -> Result<Option<Selection>> {
+        while let Some(selection) = self.next_change(deadline)? {
+            match &self.filter {
+                Some(filter) if !filter.wants(selection) => continue,
+                _ => return Ok(Some(selection)),
+            }
+        }
+        Ok(None)
+    }
+}
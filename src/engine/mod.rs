@@ -0,0 +1,765 @@
+//! The read → format → write loop shared by every OS, which only supplies a
+//! [`ClipboardBackend`], and a [`WaitingBackend`] if the engine is to wait for changes.
+
+#[cfg(test)]
+// Filters, pastes and saves are only driven by the Linux service.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) mod mock;
+mod ping_pong;
+mod stats;
+
+pub use ping_pong::*;
+pub use stats::*;
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::{
+    config::{App, Selection},
+    fmt::{Formatter, StringFormatter},
+    ipc::RuleStats,
+};
+
+/// Formatters of the enabled selections.
+pub type SharedFormatters = Arc<RwLock<HashMap<Selection, StringFormatter>>>;
+
+/// Decides which changes are read, in two steps so that the owner is only looked up for
+/// selections that are of use.
+pub trait ChangeFilter {
+    /// Whether a change of `selection` is of any use now.
+    fn wants(&self, selection: Selection) -> bool;
+
+    /// Whether a copy into `selection` by `owner` is read, `None` if the owner can not be told.
+    fn accepts(&self, selection: Selection, owner: Option<&App>) -> bool;
+}
+
+/// The clipboard of one OS, as far as [`ClipdEngine`] is concerned. Changes are handed to
+/// [`ClipdEngine::on_change`], unless it is a [`WaitingBackend`] too.
+pub trait ClipboardBackend {
+    /// The text in `selection`, `None` if it holds something else.
+    fn read_text(&mut self, selection: Selection) -> Result<Option<String>>;
+
+    /// Replaces the contents of `selection` with `text`. `false` if `selection` was copied to
+    /// while the write was prepared, and is left as is.
+    fn write_text(&mut self, selection: Selection, text: String) -> Result<bool>;
+
+    /// Whether our own writes are reported as changes too, the engine skips them then.
+    fn echoes_writes(&self) -> bool {
+        true
+    }
+
+    /// Who put what was last read into `selection`, `None` if the backend can not tell.
+    fn read_owner(&self, _selection: Selection) -> Option<String> {
+        None
+    }
+
+    /// Whether `selection` still holds what was last read from it, e.g. by the time it was
+    /// copied. `None` if the backend can not tell, the engine reads the text again then.
+    ///
+    /// Whatever it compares with is taken before the read, so that a copy during the read
+    /// counts as a change.
+    fn is_unchanged(&mut self, _selection: Selection) -> Result<Option<bool>> {
+        Ok(None)
+    }
+
+    /// Replaces the contents of `selection` with `text` the way [`ClipboardBackend::write_text`]
+    /// does, but renders it only when pasted: `format` gets the app that pastes and returns
+    /// the text it receives. `false` if `selection` was copied to since the read.
+    ///
+    /// Backends that can not tell who pastes write what `format` gives any app right away.
+    fn defer_text<F>(&mut self, selection: Selection, text: String, format: F) -> Result<bool>
+    where
+        F: Fn(&App) -> String + Send + Sync + 'static,
+    {
+        let _ = text;
+        self.write_text(selection, format(&App::default()))
+    }
+
+    /// Keeps `text`, which was read from `selection` and left as is, to serve it once its
+    /// owner is gone. Nothing by default.
+    fn save(&mut self, _selection: Selection, _text: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Owns `selection` with just `text`, whatever it holds now.
+    fn store_text(&mut self, selection: Selection, text: String) -> Result<()> {
+        self.write_text(selection, text)?;
+        Ok(())
+    }
+
+    /// The text in `selection` right now, rather than as of its last change.
+    fn read_current(&mut self, selection: Selection) -> Result<Option<String>> {
+        self.read_text(selection)
+    }
+
+    /// Whether the user is still selecting text, so that PRIMARY may be half-finished.
+    fn is_selecting(&self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// A [`ClipboardBackend`] the engine waits on for changes, see [`ClipdEngine::wait`].
+pub trait WaitingBackend: ClipboardBackend {
+    /// Blocks until a selection changed, `None` if woken or `deadline` passed first.
+    fn wait_for_change(&mut self, deadline: Option<Instant>) -> Result<Option<Selection>>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Formatted {
+    Changed(String),
+    Unchanged(String),
+    /// Changed, but not written since the selection was copied to again after the read.
+    Stale(String),
+    /// Changed, but not written while another program keeps undoing our rewrites.
+    BackedOff(String),
+}
+
+impl Formatted {
+    pub fn into_text(self) -> String {
+        match self {
+            Formatted::Changed(t)
+            | Formatted::Unchanged(t)
+            | Formatted::Stale(t)
+            | Formatted::BackedOff(t) => t,
+        }
+    }
+}
+
+/// The rules that changed a text, counted once it is written.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct Matched(Vec<String>);
+
+/// The formatters of a service and how often their rules matched, cheap to clone so that text
+/// can be formatted outside the engine too.
+#[derive(Debug, Clone)]
+pub struct Formatters {
+    shared: SharedFormatters,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Formatters {
+    pub fn new(shared: SharedFormatters) -> Self {
+        Self {
+            shared,
+            stats: Default::default(),
+        }
+    }
+
+    /// Only CLIPBOARD, formatted by `formatter`.
+    pub fn clipboard(formatter: StringFormatter) -> Self {
+        Self::new(Arc::new(RwLock::new(HashMap::from([(
+            Selection::Clipboard,
+            formatter,
+        )]))))
+    }
+
+    pub fn contains(&self, selection: Selection) -> bool {
+        self.shared.read().unwrap().contains_key(&selection)
+    }
+
+    /// Runs the formatter of `selection` if it is enabled. Counted as seen, and as rewritten
+    /// once the result is written, see [`Formatters::rewrote`].
+    pub fn format(&self, selection: Selection, text: String) -> Result<(Formatted, Matched)> {
+        self.format_counted(selection, text, true)
+    }
+
+    pub fn run(&self, formatter: &StringFormatter, text: String) -> Result<(Formatted, Matched)> {
+        self.run_counted(formatter, text, true)
+    }
+
+    /// Like [`Formatters::format`], but leaves the counters alone unless `count`, e.g. for all
+    /// but the first paste of a copy.
+    pub fn format_counted(
+        &self,
+        selection: Selection,
+        text: String,
+        count: bool,
+    ) -> Result<(Formatted, Matched)> {
+        let formatters = self.shared.read().unwrap();
+        match formatters.get(&selection) {
+            Some(formatter) => self.run_counted(formatter, text, count),
+            None => Ok((Formatted::Unchanged(text), Matched::default())),
+        }
+    }
+
+    /// Like [`Formatters::run`], but leaves the counters alone unless `count`.
+    pub fn run_counted(
+        &self,
+        formatter: &StringFormatter,
+        text: String,
+        count: bool,
+    ) -> Result<(Formatted, Matched)> {
+        let fmt_result = formatter.fmt(&text)?;
+        let mut matched = Matched::default();
+        if count {
+            self.stats.lock().unwrap().record_seen(formatter);
+            matched.0 = fmt_result.matched_rules().to_vec();
+        }
+        log::trace!("{:?}", fmt_result);
+        let formatted = match fmt_result.has_changed() {
+            true => Formatted::Changed(fmt_result.data),
+            false => Formatted::Unchanged(fmt_result.data),
+        };
+        Ok((formatted, matched))
+    }
+
+    /// The text `matched` was formatted with got written.
+    pub fn rewrote(&self, matched: Matched) {
+        if !matched.0.is_empty() {
+            self.stats.lock().unwrap().record_rewritten(&matched.0);
+        }
+    }
+
+    /// Counters of the active rules, in pipeline order.
+    pub fn rules(&self) -> Vec<RuleStats> {
+        // Same lock order as `format`.
+        let formatters = self.shared.read().unwrap();
+        let formatters = Selection::ALL.iter().filter_map(|s| formatters.get(s));
+        self.stats.lock().unwrap().rules(formatters)
+    }
+}
+
+/// Whether the engine is paused, shared with whoever pauses it from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct Pause(Arc<AtomicBool>);
+
+impl Pause {
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.0.store(paused, Ordering::SeqCst);
+    }
+}
+
+/// A burst of changes is read after this many debounce windows at the latest.
+const MAX_DEBOUNCES: u32 = 10;
+
+fn hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Reads what changed, formats it and writes it back, skipping its own writes and anything
+/// while paused.
+#[derive(Debug)]
+pub struct ClipdEngine<B> {
+    backend: B,
+    formatters: Formatters,
+    paused: Pause,
+    /// What we wrote last, by selection, until its change is seen.
+    written: HashMap<Selection, String>,
+    /// Hash of the text last read, by selection.
+    read: HashMap<Selection, u64>,
+    ping_pong: Arc<Mutex<PingPong>>,
+    debounce: Duration,
+    /// Changed selections not read yet, in order.
+    pending: VecDeque<Selection>,
+}
+
+impl<B: ClipboardBackend> ClipdEngine<B> {
+    pub fn new(backend: B, formatters: Formatters) -> Self {
+        Self {
+            backend,
+            formatters,
+            paused: Default::default(),
+            written: HashMap::new(),
+            read: HashMap::new(),
+            ping_pong: Default::default(),
+            debounce: Duration::ZERO,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn formatters(&self) -> &Formatters {
+        &self.formatters
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// The pause state, for pausing from another thread.
+    pub fn pause(&self) -> Pause {
+        self.paused.clone()
+    }
+
+    /// The rewrite loop detection, for reporting it from another thread.
+    pub fn ping_pong(&self) -> Arc<Mutex<PingPong>> {
+        self.ping_pong.clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_paused()
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.set_paused(paused);
+    }
+
+    /// Changes less than `debounce` apart are read once, after the last one. Zero reads every
+    /// change right away.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// The text `selection` changed to, `None` if paused, not text or written by us.
+    pub fn read(&mut self, selection: Selection) -> Result<Option<String>> {
+        if self.is_paused() {
+            log::trace!("Paused, ignore {}", selection.name());
+            return Ok(None);
+        }
+        let text = match self.backend.read_text(selection)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        if self.written.remove(&selection).as_ref() == Some(&text) {
+            log::trace!("Ignore our own write to {}", selection.name());
+            return Ok(None);
+        }
+        self.read.insert(selection, hash(&text));
+        Ok(Some(text))
+    }
+
+    /// Formats `text` read from `selection` and writes it back if that changed it, unless
+    /// `selection` changed since the read or rewrites are backing off.
+    pub fn rewrite(&mut self, selection: Selection, text: String) -> Result<Formatted> {
+        let read = hash(&text);
+        let (formatted, matched) = self.formatters.format(selection, text)?;
+        let text = match formatted {
+            Formatted::Changed(text) => text,
+            formatted => {
+                log::debug!("No text need formatting");
+                return Ok(formatted);
+            }
+        };
+        if !self.is_unchanged(selection)? {
+            log::debug!(
+                "{} changed while formatting, drop the result",
+                selection.name()
+            );
+            return Ok(Formatted::Stale(text));
+        }
+        if self.is_backing_off(selection, read) {
+            log::debug!("Backing off, leave {} as is", selection.name());
+            return Ok(Formatted::BackedOff(text));
+        }
+        if !self.backend.write_text(selection, text.clone())? {
+            log::debug!(
+                "{} changed while writing, drop the result",
+                selection.name()
+            );
+            return Ok(Formatted::Stale(text));
+        }
+        if self.backend.echoes_writes() {
+            self.written.insert(selection, text.clone());
+        }
+        self.formatters.rewrote(matched);
+        self.rewrote(selection, read);
+        Ok(Formatted::Changed(text))
+    }
+
+    /// Whether rewrites of the text hashed to `read` in `selection` are held back, as another
+    /// program keeps undoing them.
+    fn is_backing_off(&self, selection: Selection, read: u64) -> bool {
+        let ping_pong = self.ping_pong.lock().unwrap();
+        ping_pong.is_backing_off(selection, read, Instant::now())
+    }
+
+    /// Something else was put in place of the text hashed to `read` in `selection`.
+    fn rewrote(&self, selection: Selection, read: u64) {
+        let owner = self.backend.read_owner(selection);
+        let mut ping_pong = self.ping_pong.lock().unwrap();
+        ping_pong.rewrote(selection, read, owner.as_deref(), Instant::now());
+    }
+
+    /// Like [`ClipdEngine::rewrite`], but `text` read from `selection` is formatted when pasted,
+    /// by `format` for the app that pastes, see [`ClipboardBackend::defer_text`]. `false` if
+    /// `selection` changed since the read or rewrites are backing off.
+    pub fn defer<F>(&mut self, selection: Selection, text: &str, format: F) -> Result<bool>
+    where
+        F: Fn(&App) -> String + Send + Sync + 'static,
+    {
+        let read = hash(text);
+        if self.is_backing_off(selection, read) {
+            log::debug!("Backing off, leave {} as is", selection.name());
+            return Ok(false);
+        }
+        if !self
+            .backend
+            .defer_text(selection, text.to_owned(), format)?
+        {
+            log::debug!("{} changed since the read", selection.name());
+            return Ok(false);
+        }
+        if self.backend.echoes_writes() {
+            self.written.insert(selection, text.to_owned());
+        }
+        self.rewrote(selection, read);
+        Ok(true)
+    }
+
+    /// Keeps `text` read from `selection`, which was left as is, for once its owner is gone.
+    pub fn save(&mut self, selection: Selection, text: &str) -> Result<()> {
+        self.backend.save(selection, text)
+    }
+
+    /// Stores `text` in `to`, e.g. to keep it in sync with another selection, unless another
+    /// program keeps putting something else there. Returns whether it was stored.
+    pub fn mirror(&mut self, to: Selection, text: String) -> Result<bool> {
+        let stored = hash(&text);
+        if self.is_backing_off(to, stored) {
+            log::debug!("Backing off, leave {} as is", to.name());
+            return Ok(false);
+        }
+        log::debug!("Mirror to {}", to.name());
+        if self.backend.echoes_writes() {
+            self.written.insert(to, text.clone());
+        }
+        self.backend.store_text(to, text)?;
+        self.rewrote(to, stored);
+        Ok(true)
+    }
+
+    /// Whether `selection` still holds what [`ClipdEngine::read`] got from it last, compared by
+    /// text if the backend can not tell. Asked once per read, `true` for anything not read by us.
+    pub fn is_unchanged(&mut self, selection: Selection) -> Result<bool> {
+        let read = match self.read.remove(&selection) {
+            Some(h) => h,
+            // Not read by us, nothing to compare with.
+            None => return Ok(true),
+        };
+        if let Some(unchanged) = self.backend.is_unchanged(selection)? {
+            return Ok(unchanged);
+        }
+        let text = self.backend.read_text(selection)?;
+        Ok(text.map(|t| hash(&t)) == Some(read))
+    }
+
+    /// Reads and rewrites `selection`, for backends that learn about changes on their own.
+    pub fn on_change(&mut self, selection: Selection) -> Result<Option<Formatted>> {
+        match self.read(selection)? {
+            Some(text) => self.rewrite(selection, text).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<B: WaitingBackend> ClipdEngine<B> {
+    /// Waits for a change and reads it, `None` if there is nothing to format.
+    pub fn wait(&mut self, deadline: Option<Instant>) -> Result<Option<(Selection, String)>> {
+        if self.pending.is_empty() {
+            match self.backend.wait_for_change(deadline)? {
+                Some(s) => self.pending.push_back(s),
+                None => return Ok(None),
+            }
+            self.settle()?;
+        }
+        let selection = match self.pending.pop_front() {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        Ok(self.read(selection)?.map(|text| (selection, text)))
+    }
+
+    /// Collects further changes until none came for the debounce window, so that a burst of
+    /// them is read once.
+    fn settle(&mut self) -> Result<()> {
+        if self.debounce.is_zero() {
+            return Ok(());
+        }
+        // Gives up on quiet eventually, e.g. while a selection is being dragged.
+        let until = Instant::now() + self.debounce * MAX_DEBOUNCES;
+        let mut coalesced = 0;
+        while Instant::now() < until {
+            let deadline = (Instant::now() + self.debounce).min(until);
+            let selection = match self.backend.wait_for_change(Some(deadline))? {
+                Some(s) => s,
+                None => break,
+            };
+            if self.pending.contains(&selection) {
+                coalesced += 1;
+            } else {
+                self.pending.push_back(selection);
+            }
+        }
+        if coalesced > 0 {
+            log::debug!("Coalesced {} changes", coalesced);
+        }
+        Ok(())
+    }
+
+    /// Waits for a change and rewrites it, `None` if there was nothing to format.
+    pub fn step(&mut self, deadline: Option<Instant>) -> Result<Option<(Selection, Formatted)>> {
+        match self.wait(deadline)? {
+            Some((selection, text)) => Ok(Some((selection, self.rewrite(selection, text)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        mock::{MockBackend, CLIPD},
+        ClipdEngine, Formatted, Formatters,
+    };
+    use crate::{
+        config::Selection,
+        fmt::{StringFormatter, TrimCr, TrimEnd},
+    };
+
+    fn new_engine(backend: MockBackend) -> ClipdEngine<MockBackend> {
+        let formatter =
+            StringFormatter::from_rules(vec![Box::new(TrimCr), Box::new(TrimEnd::Whitespace)]);
+        ClipdEngine::new(backend, Formatters::clipboard(formatter))
+    }
+
+    /// Steps through the whole script, returns the errors.
+    fn run(engine: &mut ClipdEngine<MockBackend>) -> Vec<String> {
+        let mut errors = vec![];
+        while !engine.backend().is_done() {
+            if let Err(e) = engine.step(None) {
+                errors.push(e.to_string());
+            }
+        }
+        errors
+    }
+
+    #[test]
+    fn rewrite() {
+        let mut engine = new_engine(
+            MockBackend::new()
+                .copy(Selection::Clipboard, "editor", "a\r\nb\n")
+                .copy(Selection::Clipboard, "terminal", "c")
+                .copy(Selection::Primary, "terminal", "d\n")
+                .copy_other(Selection::Clipboard, "viewer"),
+        );
+        assert!(run(&mut engine).is_empty());
+
+        let backend = engine.backend();
+        assert_eq!(backend.writes(), [(Selection::Clipboard, "a\nb".into())]);
+        // The echo of our write is read, but not formatted again.
+        assert_eq!(backend.reads(), 5);
+        assert_eq!(backend.owner(Selection::Clipboard), Some("viewer"));
+        assert_eq!(backend.text(Selection::Primary), Some("d\n"));
+
+        let counts = engine
+            .formatters
+            .rules()
+            .into_iter()
+            .map(|r| (r.name, r.seen, r.rewritten))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("trim-cr".to_owned(), 2, 1),
+                ("trim-end-whitespace".to_owned(), 2, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn own_writes() {
+        let mut engine = new_engine(
+            MockBackend::new()
+                .copy(Selection::Clipboard, "editor", "a\n")
+                .copy(Selection::Clipboard, "editor", "a"),
+        );
+        assert_eq!(
+            engine.step(None).unwrap(),
+            Some((Selection::Clipboard, Formatted::Changed("a".into())))
+        );
+        assert_eq!(engine.backend().owner(Selection::Clipboard), Some(CLIPD));
+        assert_eq!(engine.step(None).unwrap(), None);
+        // Copying the same text again is a change of its own.
+        assert_eq!(
+            engine.step(None).unwrap(),
+            Some((Selection::Clipboard, Formatted::Unchanged("a".into())))
+        );
+        assert_eq!(engine.backend().writes().len(), 1);
+
+        // Nothing to skip if our writes are not reported.
+        let mut engine = new_engine(
+            MockBackend::new()
+                .without_echo()
+                .copy(Selection::Clipboard, "editor", "a\n")
+                .copy(Selection::Clipboard, "editor", "a"),
+        );
+        assert!(run(&mut engine).is_empty());
+        assert_eq!(engine.backend().writes().len(), 1);
+        assert_eq!(engine.backend().reads(), 2);
+    }
+
+    #[test]
+    fn stale() {
+        for backend in [MockBackend::new(), MockBackend::new().without_versions()] {
+            let mut engine = new_engine(
+                backend
+                    .copy(Selection::Clipboard, "editor", "a\n")
+                    .copy_after_read(Selection::Clipboard, "browser", "b\n"),
+            );
+            assert_eq!(
+                engine.step(None).unwrap(),
+                Some((Selection::Clipboard, Formatted::Stale("a".into())))
+            );
+            assert_eq!(engine.backend().text(Selection::Clipboard), Some("b\n"));
+            assert!(engine.backend().writes().is_empty());
+
+            // The newer copy is formatted instead.
+            assert!(run(&mut engine).is_empty());
+            assert_eq!(
+                engine.backend().writes(),
+                [(Selection::Clipboard, "b".into())]
+            );
+        }
+    }
+
+    #[test]
+    fn copy_during_write() {
+        let mut engine = new_engine(
+            MockBackend::new()
+                .copy(Selection::Clipboard, "editor", "a\n")
+                .copy_during_write(Selection::Clipboard, "browser", "b\n"),
+        );
+        assert_eq!(
+            engine.step(None).unwrap(),
+            Some((Selection::Clipboard, Formatted::Stale("a".into())))
+        );
+        assert_eq!(engine.backend().text(Selection::Clipboard), Some("b\n"));
+        assert!(engine.backend().writes().is_empty());
+
+        assert!(run(&mut engine).is_empty());
+        assert_eq!(
+            engine.backend().writes(),
+            [(Selection::Clipboard, "b".into())]
+        );
+        // Only what was written counts as rewritten.
+        let rule = engine.formatters().rules().pop().unwrap();
+        assert_eq!((rule.seen, rule.rewritten), (2, 1));
+    }
+
+    #[test]
+    fn ping_pong() {
+        // Another tool puts the trailing newline back every time.
+        let mut backend = MockBackend::new().copy(Selection::Clipboard, "editor", "a\n");
+        for _ in 0..7 {
+            backend = backend.copy(Selection::Clipboard, "manager", "a\n");
+        }
+        let mut engine = new_engine(backend.copy(Selection::Clipboard, "editor", "b\n"));
+        assert!(run(&mut engine).is_empty());
+
+        // Only that text is left alone.
+        let backend = engine.backend();
+        assert_eq!(backend.writes().len(), 7);
+        assert_eq!(backend.text(Selection::Clipboard), Some("b"));
+        let status = engine.ping_pong.lock().unwrap().status(Instant::now());
+        assert_eq!(status.unwrap().selection, "clipboard");
+    }
+
+    #[test]
+    fn copy_again() {
+        let mut backend = MockBackend::new();
+        for _ in 0..8 {
+            backend = backend.copy(Selection::Clipboard, "editor", "a\n");
+        }
+        let mut engine = new_engine(backend);
+        assert!(run(&mut engine).is_empty());
+        assert_eq!(engine.backend().writes().len(), 8);
+        let status = engine.ping_pong.lock().unwrap().status(Instant::now());
+        assert!(status.is_none());
+    }
+
+    #[test]
+    fn debounce() {
+        let mut engine = new_engine(
+            MockBackend::new()
+                .copy(Selection::Clipboard, "terminal", "a\n")
+                .copy(Selection::Clipboard, "terminal", "a\n")
+                .copy(Selection::Primary, "terminal", "b")
+                .copy(Selection::Clipboard, "terminal", "c\n")
+                .quiet()
+                .copy(Selection::Clipboard, "editor", "d\n"),
+        );
+        engine.set_debounce(Duration::from_millis(50));
+        // Each selection of the burst is read once, with what it holds last.
+        assert_eq!(
+            engine.step(None).unwrap(),
+            Some((Selection::Clipboard, Formatted::Changed("c".into())))
+        );
+        assert_eq!(
+            engine.step(None).unwrap(),
+            Some((Selection::Primary, Formatted::Unchanged("b".into())))
+        );
+        assert_eq!(engine.backend().reads(), 2);
+
+        assert!(run(&mut engine).is_empty());
+        assert_eq!(
+            engine.backend().writes(),
+            [
+                (Selection::Clipboard, "c".into()),
+                (Selection::Clipboard, "d".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn paused() {
+        let mut engine = new_engine(
+            MockBackend::new()
+                .copy(Selection::Clipboard, "editor", "a\n")
+                .copy(Selection::Clipboard, "editor", "b\n"),
+        );
+        engine.set_paused(true);
+        assert_eq!(engine.step(None).unwrap(), None);
+        engine.set_paused(false);
+        assert!(run(&mut engine).is_empty());
+        assert_eq!(
+            engine.backend().writes(),
+            [(Selection::Clipboard, "b".into())]
+        );
+        assert_eq!(engine.backend().reads(), 2);
+    }
+
+    #[test]
+    fn failures() {
+        let mut engine = new_engine(
+            MockBackend::new()
+                .fail_reads(1)
+                .fail_writes(1)
+                .copy(Selection::Clipboard, "editor", "a\n")
+                .copy(Selection::Clipboard, "editor", "b\n")
+                .fail("Connection lost")
+                .copy(Selection::Clipboard, "editor", "c\n"),
+        );
+        assert_eq!(
+            run(&mut engine),
+            [
+                "Read clipboard failed",
+                "Write clipboard failed",
+                "Connection lost"
+            ]
+        );
+        let backend = engine.backend();
+        assert_eq!(backend.writes(), [(Selection::Clipboard, "c".into())]);
+        assert_eq!(backend.owner(Selection::Clipboard), Some(CLIPD));
+    }
+}
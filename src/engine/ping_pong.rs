@@ -0,0 +1,207 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{config::Selection, ipc::PingPongStatus};
+
+/// Rewrites of the same text within this long count as one loop.
+const WINDOW: Duration = Duration::from_secs(5);
+/// Rewrites of the same text within [`WINDOW`] that make a loop.
+const LIMIT: usize = 5;
+const FIRST_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Notices when the same text is rewritten over and over, as happens when another clipboard
+/// tool undoes our rewrites, and then holds rewrites of that text back for a while.
+#[derive(Debug)]
+pub struct PingPong {
+    window: Duration,
+    limit: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    /// Recent rewrites.
+    rewrites: VecDeque<Rewrite>,
+    /// The text last rewritten in each selection, by hash, and who copied it first.
+    origins: HashMap<Selection, (u64, Option<String>)>,
+    /// The selection and text that loop, and until when their rewrites are held back.
+    backing_off: Option<(Selection, u64, Instant)>,
+    warned: bool,
+}
+
+#[derive(Debug)]
+struct Rewrite {
+    time: Instant,
+    selection: Selection,
+    /// Hash of the text replaced.
+    read: u64,
+    /// Who put that text there.
+    owner: Option<String>,
+}
+
+impl Default for PingPong {
+    fn default() -> Self {
+        Self::new(WINDOW, LIMIT, FIRST_BACKOFF)
+    }
+}
+
+impl PingPong {
+    /// `limit` rewrites of the same text within `window` hold rewrites of it back for
+    /// `backoff`, twice as long each time it happens again.
+    pub fn new(window: Duration, limit: usize, backoff: Duration) -> Self {
+        Self {
+            window,
+            limit,
+            backoff,
+            max_backoff: MAX_BACKOFF.max(backoff),
+            rewrites: VecDeque::new(),
+            origins: HashMap::new(),
+            backing_off: None,
+            warned: false,
+        }
+    }
+
+    /// Whether rewrites of the text hashed to `read` in `selection` are held back at `now`.
+    pub fn is_backing_off(&self, selection: Selection, read: u64, now: Instant) -> bool {
+        matches!(
+            self.backing_off,
+            Some((s, r, until)) if s == selection && r == read && now < until
+        )
+    }
+
+    /// The text hashed to `read` in `selection`, put there by `owner`, was rewritten at `now`.
+    /// Each owner that puts the text back counts apart, except the one it was first copied
+    /// from: that is someone copying the same text again rather than a loop. Owners that can
+    /// not be told, `None`, always count.
+    pub fn rewrote(&mut self, selection: Selection, read: u64, owner: Option<&str>, now: Instant) {
+        while let Some(rewrite) = self.rewrites.front() {
+            if now.duration_since(rewrite.time) <= self.window {
+                break;
+            }
+            self.rewrites.pop_front();
+        }
+        match self.origins.get(&selection) {
+            Some((r, origin)) if *r == read => {
+                if owner.is_some() && origin.as_deref() == owner {
+                    return;
+                }
+            }
+            _ => {
+                self.origins
+                    .insert(selection, (read, owner.map(str::to_owned)));
+            }
+        }
+        self.rewrites.push_back(Rewrite {
+            time: now,
+            selection,
+            read,
+            owner: owner.map(str::to_owned),
+        });
+        let repeated = self
+            .rewrites
+            .iter()
+            .filter(|r| r.selection == selection && r.read == read && r.owner.as_deref() == owner)
+            .count();
+        if repeated < self.limit {
+            return;
+        }
+
+        if !self.warned {
+            log::warn!(
+                "{} was rewritten {} times in {:?}, {} seems to undo our rewrites. Stop \
+                 rewriting it for {:?}",
+                selection.name(),
+                repeated,
+                self.window,
+                owner.unwrap_or("another program"),
+                self.backoff
+            );
+            self.warned = true;
+        } else {
+            log::debug!("Rewrite loop again, back off for {:?}", self.backoff);
+        }
+        self.backing_off = Some((selection, read, now + self.backoff));
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+        self.rewrites.clear();
+    }
+
+    pub fn status(&self, now: Instant) -> Option<PingPongStatus> {
+        match self.backing_off {
+            Some((selection, _, until)) if now < until => Some(PingPongStatus {
+                selection: selection.name().to_owned(),
+                resume_in_secs: until.duration_since(now).as_secs(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::PingPong;
+    use crate::config::Selection;
+
+    #[test]
+    fn ping_pong() {
+        let secs = Duration::from_secs;
+        let mut ping_pong = PingPong::new(secs(5), 3, secs(30));
+        let start = Instant::now();
+        let clipboard = Selection::Clipboard;
+
+        // Different texts, or the same one too far apart, are no loop.
+        ping_pong.rewrote(clipboard, 1, None, start);
+        ping_pong.rewrote(clipboard, 2, None, start);
+        ping_pong.rewrote(Selection::Primary, 1, None, start);
+        ping_pong.rewrote(clipboard, 1, None, start + secs(6));
+        ping_pong.rewrote(clipboard, 1, None, start + secs(7));
+        assert!(!ping_pong.is_backing_off(clipboard, 1, start + secs(7)));
+
+        ping_pong.rewrote(clipboard, 1, None, start + secs(8));
+        assert!(ping_pong.is_backing_off(clipboard, 1, start + secs(8)));
+        // Only for the text that loops.
+        assert!(!ping_pong.is_backing_off(clipboard, 2, start + secs(8)));
+        assert!(!ping_pong.is_backing_off(Selection::Primary, 1, start + secs(8)));
+        let status = ping_pong.status(start + secs(10)).unwrap();
+        assert_eq!(status.selection, "clipboard");
+        assert_eq!(status.resume_in_secs, 28);
+        assert!(!ping_pong.is_backing_off(clipboard, 1, start + secs(38)));
+        assert!(ping_pong.status(start + secs(38)).is_none());
+
+        // Twice as long the next time.
+        for i in 0..3 {
+            ping_pong.rewrote(clipboard, 1, None, start + secs(40 + i));
+        }
+        assert!(ping_pong.is_backing_off(clipboard, 1, start + secs(101)));
+        assert!(!ping_pong.is_backing_off(clipboard, 1, start + secs(102)));
+    }
+
+    #[test]
+    fn owners() {
+        let secs = Duration::from_secs;
+        let mut ping_pong = PingPong::new(secs(5), 3, secs(30));
+        let start = Instant::now();
+        let clipboard = Selection::Clipboard;
+
+        // Copying the same text again and again is no loop.
+        for i in 0..5 {
+            ping_pong.rewrote(clipboard, 1, Some("editor"), start + secs(i));
+        }
+        assert!(!ping_pong.is_backing_off(clipboard, 1, start + secs(4)));
+
+        // Another program putting it back each time is, counted apart from other owners.
+        ping_pong.rewrote(clipboard, 1, Some("manager"), start + secs(4));
+        ping_pong.rewrote(clipboard, 1, Some("manager"), start + secs(4));
+        ping_pong.rewrote(clipboard, 1, Some("viewer"), start + secs(4));
+        assert!(!ping_pong.is_backing_off(clipboard, 1, start + secs(4)));
+        ping_pong.rewrote(clipboard, 1, Some("manager"), start + secs(4));
+        assert!(ping_pong.is_backing_off(clipboard, 1, start + secs(4)));
+
+        // Still told apart from the editor once the backoff is over.
+        for i in 0..3 {
+            ping_pong.rewrote(clipboard, 1, Some("manager"), start + secs(40 + i));
+        }
+        assert!(ping_pong.is_backing_off(clipboard, 1, start + secs(42)));
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{fmt::StringFormatter, ipc::RuleStats};
+
+/// Per-rule counters, keyed by name so that they survive a reload.
+#[derive(Debug, Default)]
+pub struct Stats {
+    rules: HashMap<String, (u64, u64)>,
+}
+
+impl Stats {
+    /// `formatter` ran on a text.
+    pub fn record_seen(&mut self, formatter: &StringFormatter) {
+        for name in formatter.rules() {
+            self.rules.entry(name.to_owned()).or_default().0 += 1;
+        }
+    }
+
+    /// A text the rules `matched` changed was written.
+    pub fn record_rewritten(&mut self, matched: &[String]) {
+        for name in matched {
+            self.rules.entry(name.clone()).or_default().1 += 1;
+        }
+    }
+
+    /// Counters of the rules `formatters` run, each rule once in order of appearance.
+    pub fn rules<'a, I>(&self, formatters: I) -> Vec<RuleStats>
+    where
+        I: IntoIterator<Item = &'a StringFormatter>,
+    {
+        let mut names = HashSet::new();
+        formatters
+            .into_iter()
+            .flat_map(StringFormatter::rules)
+            .filter(|name| names.insert(*name))
+            .map(|name| {
+                let (seen, rewritten) = self.rules.get(name).copied().unwrap_or_default();
+                RuleStats {
+                    name: name.to_owned(),
+                    seen,
+                    rewritten,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use crate::fmt::{Formatter, StringFormatter, TrimCr, TrimEnd};
+
+    #[test]
+    fn record() {
+        let formatter = StringFormatter::from_rules(vec![Box::new(TrimCr), Box::new(TrimEnd::Lf)]);
+        let primary = StringFormatter::from_rules(vec![Box::new(TrimEnd::Lf)]);
+        let mut stats = Stats::default();
+        for text in ["a\r\n", "b", "c\n"] {
+            let result = formatter.fmt_unckecked(&text.to_string());
+            stats.record_seen(&formatter);
+            stats.record_rewritten(result.matched_rules());
+        }
+        stats.record_seen(&primary);
+        let counts = stats
+            .rules([&primary, &formatter])
+            .into_iter()
+            .map(|r| (r.name, r.seen, r.rewritten))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("trim-end-lf".to_owned(), 4, 2),
+                ("trim-cr".to_owned(), 3, 1)
+            ]
+        );
+    }
+}
@@ -1,8 +1,14 @@
+mod pipeline;
+mod replace;
+mod rule;
 mod unicode;
 
-use unicode::*;
+pub use pipeline::*;
+pub use replace::*;
+pub use rule::*;
+pub use unicode::*;
 
-pub type StringFormatter = UnicodeFormatter;
+pub type StringFormatter = FormatPipeline;
 
 use anyhow::{bail, Result};
 
@@ -36,6 +42,25 @@ impl FormatFeature {
         }
         Ok(self)
     }
+
+    /// Built-in rules enabled by this feature set, in the order they run.
+    pub fn rules(&self) -> Vec<Box<dyn FormatRule>> {
+        let mut rules: Vec<Box<dyn FormatRule>> = vec![];
+        if self.contains(Self::TRIM_CR) {
+            rules.push(Box::new(TrimCr));
+        }
+        if self.contains(Self::TRIM_START_WHITESPACE) {
+            rules.push(Box::new(TrimStart::Whitespace));
+        } else if self.contains(Self::TRIM_START_LF) {
+            rules.push(Box::new(TrimStart::Lf));
+        }
+        if self.contains(Self::TRIM_END_WHITESPACE) {
+            rules.push(Box::new(TrimEnd::Whitespace));
+        } else if self.contains(Self::TRIM_END_LF) {
+            rules.push(Box::new(TrimEnd::Lf));
+        }
+        rules
+    }
 }
 
 impl Default for FormatFeature {
@@ -47,23 +72,28 @@ impl Default for FormatFeature {
 #[derive(Debug)]
 pub struct FormatResult<S> {
     pub data: S,
-    matched_feature: FormatFeature,
+    matched_rules: Vec<String>,
 }
 
 impl<S> FormatResult<S> {
-    pub fn new(data: S, matched_feature: FormatFeature) -> Self {
+    pub fn new(data: S, matched_rules: Vec<String>) -> Self {
         Self {
             data,
-            matched_feature,
+            matched_rules,
         }
     }
 
     pub fn has_changed(&self) -> bool {
-        return !self.matched_feature.is_empty();
+        !self.matched_rules.is_empty()
+    }
+
+    /// Names of the rules that changed the text, in the order they ran.
+    pub fn matched_rules(&self) -> &[String] {
+        &self.matched_rules
     }
 
     pub fn map<U, F: FnOnce(S) -> U>(self, op: F) -> FormatResult<U> {
-        FormatResult::new(op(self.data), self.matched_feature)
+        FormatResult::new(op(self.data), self.matched_rules)
     }
 }
 
@@ -73,11 +103,11 @@ where
 {
     fn new(feature: FormatFeature) -> Result<Self>;
     fn new_unchecked(feature: FormatFeature) -> Self {
-        Self::new(feature).expect(&format!("Formatter with {:?}", feature))
+        Self::new(feature).unwrap_or_else(|e| panic!("Formatter with {:?}: {:?}", feature, e))
     }
 
     fn fmt(&self, text: &S) -> Result<FormatResult<R>>;
     fn fmt_unckecked(&self, text: &S) -> FormatResult<R> {
-        Formatter::fmt(self, text).expect(&format!("{:?} fmt {:?}", self, text))
+        Formatter::fmt(self, text).unwrap_or_else(|e| panic!("{:?} fmt {:?}: {:?}", self, text, e))
     }
 }
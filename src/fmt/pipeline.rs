@@ -0,0 +1,118 @@
+use anyhow::{bail, Result};
+
+use super::{FormatFeature, FormatResult, FormatRule, Formatter};
+
+/// Runs [`FormatRule`]s in order, each one sees the output of the previous one.
+#[derive(Debug)]
+pub struct FormatPipeline {
+    rules: Vec<Box<dyn FormatRule>>,
+    ends_with_zero: bool,
+}
+
+impl FormatPipeline {
+    pub fn from_rules(rules: Vec<Box<dyn FormatRule>>) -> Self {
+        Self {
+            rules,
+            ends_with_zero: false,
+        }
+    }
+
+    pub fn with_rule<R: FormatRule + 'static>(mut self, rule: R) -> Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    pub fn ends_with_zero(mut self) -> Self {
+        self.ends_with_zero = true;
+        self
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|r| r.name())
+    }
+}
+
+impl Default for FormatPipeline {
+    fn default() -> Self {
+        Self::from_rules(FormatFeature::default().rules())
+    }
+}
+
+impl Formatter<String, String> for FormatPipeline {
+    fn new(feature: FormatFeature) -> Result<Self> {
+        Ok(Self::from_rules(feature.expect()?.rules()))
+    }
+
+    fn fmt(&self, text: &String) -> Result<FormatResult<String>> {
+        if self.rules.is_empty() {
+            bail!("FormatPipeline is empty")
+        }
+
+        let mut data = match text.find('\0') {
+            Some(end) => text[..end].to_owned(),
+            None => text.clone(),
+        };
+        let mut matched_rules = vec![];
+        for rule in &self.rules {
+            if rule.apply(&mut data) {
+                matched_rules.push(rule.name().to_owned());
+            }
+        }
+        if self.ends_with_zero {
+            data.push('\0');
+        }
+        Ok(FormatResult::new(data, matched_rules))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FormatRule, Formatter, StringFormatter, TrimCr, TrimEnd};
+
+    #[derive(Debug)]
+    struct Upper;
+
+    impl FormatRule for Upper {
+        fn name(&self) -> &str {
+            "upper"
+        }
+
+        fn apply(&self, text: &mut String) -> bool {
+            let upper = text.to_uppercase();
+            if upper == *text {
+                return false;
+            }
+            *text = upper;
+            true
+        }
+    }
+
+    #[test]
+    fn rules_run_in_order() {
+        let formatter = StringFormatter::from_rules(vec![])
+            .with_rule(TrimCr)
+            .with_rule(Upper)
+            .with_rule(TrimEnd::Lf);
+        assert_eq!(
+            formatter.rules().collect::<Vec<_>>(),
+            vec!["trim-cr", "upper", "trim-end-lf"]
+        );
+
+        let fmt_result = formatter.fmt_unckecked(&"a\r\nb\n".to_string());
+        assert_eq!(fmt_result.data, "A\nB");
+        assert_eq!(
+            fmt_result.matched_rules(),
+            ["trim-cr", "upper", "trim-end-lf"]
+        );
+
+        let fmt_result = formatter.fmt_unckecked(&"AB\n".to_string());
+        assert_eq!(fmt_result.data, "AB");
+        assert_eq!(fmt_result.matched_rules(), ["trim-end-lf"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_pipeline() {
+        StringFormatter::from_rules(vec![]).fmt_unckecked(&"foo".to_string());
+    }
+}
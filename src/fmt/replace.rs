@@ -0,0 +1,187 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use super::FormatRule;
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct RegexFlags: u32 {
+        /// `i`
+        const CASE_INSENSITIVE = 1 << 0;
+        /// `m`: `^` and `$` match at line boundaries
+        const MULTI_LINE = 1 << 1;
+        /// `s`: `.` matches `\n`
+        const DOT_MATCHES_NEW_LINE = 1 << 2;
+        /// `x`: ignore whitespace and allow `#` comments
+        const IGNORE_WHITESPACE = 1 << 3;
+        /// `U`: swap the meaning of `x*` and `x*?`
+        const SWAP_GREED = 1 << 4;
+    }
+}
+
+impl FromStr for RegexFlags {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut flags = RegexFlags::empty();
+        for c in s.chars() {
+            flags |= match c {
+                'i' => RegexFlags::CASE_INSENSITIVE,
+                'm' => RegexFlags::MULTI_LINE,
+                's' => RegexFlags::DOT_MATCHES_NEW_LINE,
+                'x' => RegexFlags::IGNORE_WHITESPACE,
+                'U' => RegexFlags::SWAP_GREED,
+                _ => bail!("Unknown regex flag: {:?}", c),
+            };
+        }
+        Ok(flags)
+    }
+}
+
+/// Replaces every match of a regex, `$1` and `${name}` in the replacement expand to capture
+/// groups.
+#[derive(Debug, Clone)]
+pub struct RegexRule {
+    name: String,
+    regex: Regex,
+    replacement: String,
+    guard: Option<Regex>,
+}
+
+impl RegexRule {
+    pub fn new(name: &str, pattern: &str, replacement: &str, flags: RegexFlags) -> Result<Self> {
+        Ok(Self {
+            name: name.to_owned(),
+            regex: build_regex(pattern, flags).with_context(|| format!("Regex rule {:?}", name))?,
+            replacement: replacement.to_owned(),
+            guard: None,
+        })
+    }
+
+    /// Only rewrite when the whole text matches the pattern.
+    pub fn whole_match(mut self) -> Self {
+        let pattern = format!(r"\A(?:{})\z", self.regex.as_str());
+        // `pattern` already compiled, the anchored form can not fail.
+        self.guard = Some(Regex::new(&pattern).unwrap());
+        self
+    }
+}
+
+impl FormatRule for RegexRule {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&self, text: &mut String) -> bool {
+        if let Some(guard) = &self.guard {
+            if !guard.is_match(text) {
+                return false;
+            }
+        }
+        let replaced = self.regex.replace_all(text, self.replacement.as_str());
+        if replaced == text.as_str() {
+            return false;
+        }
+        *text = replaced.into_owned();
+        true
+    }
+}
+
+fn build_regex(pattern: &str, flags: RegexFlags) -> Result<Regex> {
+    // Flags are inlined so that `Regex::as_str` keeps them for `whole_match`.
+    let mut inline = String::new();
+    for (flag, c) in [
+        (RegexFlags::CASE_INSENSITIVE, 'i'),
+        (RegexFlags::MULTI_LINE, 'm'),
+        (RegexFlags::DOT_MATCHES_NEW_LINE, 's'),
+        (RegexFlags::IGNORE_WHITESPACE, 'x'),
+        (RegexFlags::SWAP_GREED, 'U'),
+    ] {
+        if flags.contains(flag) {
+            inline.push(c);
+        }
+    }
+    let pattern = if inline.is_empty() {
+        pattern.to_owned()
+    } else {
+        format!("(?{}){}", inline, pattern)
+    };
+    Ok(Regex::new(&pattern)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{FormatRule, Formatter, StringFormatter, TrimStart};
+    use super::{RegexFlags, RegexRule};
+
+    fn apply(rule: &RegexRule, text: &str) -> (bool, String) {
+        let mut text = text.to_owned();
+        (rule.apply(&mut text), text)
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!("".parse::<RegexFlags>().unwrap(), RegexFlags::empty());
+        assert_eq!(
+            "im".parse::<RegexFlags>().unwrap(),
+            RegexFlags::CASE_INSENSITIVE | RegexFlags::MULTI_LINE
+        );
+        assert!("q".parse::<RegexFlags>().is_err());
+        assert!(RegexRule::new("bad", "(", "", RegexFlags::empty()).is_err());
+    }
+
+    #[test]
+    fn capture_groups() {
+        let rule = RegexRule::new(
+            "ticket",
+            r"^(?P<key>[A-Z]+)-(\d+): ",
+            "[$key#$2] ",
+            RegexFlags::MULTI_LINE,
+        )
+        .unwrap();
+        assert_eq!(
+            apply(&rule, "ABC-12: foo\nDE-3: bar"),
+            (true, "[ABC#12] foo\n[DE#3] bar".to_owned())
+        );
+        assert_eq!(apply(&rule, "foo"), (false, "foo".to_owned()));
+
+        let rule = RegexRule::new("same", "a", "a", RegexFlags::empty()).unwrap();
+        assert_eq!(apply(&rule, "abc"), (false, "abc".to_owned()));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let rule = RegexRule::new("utm", r"[?&]utm_\w+=[^&]*", "", "i".parse().unwrap()).unwrap();
+        assert_eq!(
+            apply(&rule, "https://x.org/?UTM_source=a&utm_medium=b"),
+            (true, "https://x.org/".to_owned())
+        );
+    }
+
+    #[test]
+    fn whole_match() {
+        let rule = RegexRule::new("url", r"https?://\S+?/", "", "i".parse().unwrap())
+            .unwrap()
+            .whole_match();
+        assert_eq!(apply(&rule, "HTTP://x.org/"), (true, "".to_owned()));
+        assert_eq!(
+            apply(&rule, "see http://x.org/"),
+            (false, "see http://x.org/".to_owned())
+        );
+    }
+
+    #[test]
+    fn in_pipeline() {
+        let formatter = StringFormatter::from_rules(vec![])
+            .with_rule(TrimStart::Whitespace)
+            .with_rule(RegexRule::new("ticket", r"^#\d+ ", "", RegexFlags::empty()).unwrap());
+        let fmt_result = formatter.fmt_unckecked(&"\n#42 fix".to_string());
+        assert_eq!(fmt_result.data, "fix");
+        assert_eq!(
+            fmt_result.matched_rules(),
+            [TrimStart::WHITESPACE, "ticket"]
+        );
+    }
+}
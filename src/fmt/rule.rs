@@ -0,0 +1,11 @@
+use std::fmt::Debug;
+
+/// A single text transformation in a [`FormatPipeline`](super::FormatPipeline).
+pub trait FormatRule: Debug + Send + Sync {
+    /// Name used in logs, [`FormatResult::matched_rules`](super::FormatResult::matched_rules)
+    /// and configuration.
+    fn name(&self) -> &str;
+
+    /// Rewrites `text` in place, returns `true` if the rule changed it.
+    fn apply(&self, text: &mut String) -> bool;
+}
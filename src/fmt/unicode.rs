@@ -1,83 +1,116 @@
-use std::{collections::VecDeque, fmt::Debug};
+use super::FormatRule;
 
-use anyhow::Result;
+const CR: char = '\x0D';
+const LF: char = '\x0A';
 
-use super::{FormatFeature, FormatResult, Formatter};
+/// Names of the built-in rules, in their default order.
+pub const BUILTIN_RULES: &[&str] = &[
+    TrimCr::NAME,
+    TrimStart::LF,
+    TrimStart::WHITESPACE,
+    TrimEnd::LF,
+    TrimEnd::WHITESPACE,
+];
 
-#[derive(Debug, Default)]
-pub struct UnicodeFormatter {
-    feature: FormatFeature,
-    ends_with_zero: bool,
+/// Looks up a built-in rule by its [`FormatRule::name`].
+pub fn builtin_rule(name: &str) -> Option<Box<dyn FormatRule>> {
+    let rule: Box<dyn FormatRule> = match name {
+        TrimCr::NAME => Box::new(TrimCr),
+        TrimStart::LF => Box::new(TrimStart::Lf),
+        TrimStart::WHITESPACE => Box::new(TrimStart::Whitespace),
+        TrimEnd::LF => Box::new(TrimEnd::Lf),
+        TrimEnd::WHITESPACE => Box::new(TrimEnd::Whitespace),
+        _ => return None,
+    };
+    Some(rule)
 }
 
-impl UnicodeFormatter {
-    pub fn ends_with_zero(mut self) -> Self {
-        self.ends_with_zero = true;
-        self
+/// Removes every `<CR>`.
+#[derive(Debug, Clone, Copy)]
+pub struct TrimCr;
+
+impl TrimCr {
+    pub const NAME: &'static str = "trim-cr";
+}
+
+impl FormatRule for TrimCr {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn apply(&self, text: &mut String) -> bool {
+        if !text.contains(CR) {
+            return false;
+        }
+        text.retain(|c| c != CR);
+        true
     }
 }
 
-impl Formatter<String, String> for UnicodeFormatter {
-    fn new(feature: FormatFeature) -> Result<Self> {
-        Ok(Self {
-            feature,
-            ends_with_zero: false,
-        })
+/// Removes leading `<LF>`s or leading whitespace.
+#[derive(Debug, Clone, Copy)]
+pub enum TrimStart {
+    Lf,
+    Whitespace,
+}
+
+impl TrimStart {
+    pub const LF: &'static str = "trim-start-lf";
+    pub const WHITESPACE: &'static str = "trim-start-whitespace";
+}
+
+impl FormatRule for TrimStart {
+    fn name(&self) -> &str {
+        match self {
+            TrimStart::Lf => Self::LF,
+            TrimStart::Whitespace => Self::WHITESPACE,
+        }
     }
 
-    fn fmt(&self, text: &String) -> Result<FormatResult<String>> {
-        let feature = self.feature.expect()?;
-        let mut matched_feature = FormatFeature::empty();
-
-        const CR: char = '\x0D';
-        const LF: char = '\x0A';
-
-        let mut deque: VecDeque<char> = VecDeque::new();
-        for char in text.chars() {
-            if char == '\0' {
-                break;
-            }
-
-            if deque.is_empty() {
-                if char == LF && feature.contains(FormatFeature::TRIM_START_LF) {
-                    matched_feature = matched_feature.union(FormatFeature::TRIM_START_LF);
-                    continue;
-                } else if char.is_whitespace()
-                    && feature.contains(FormatFeature::TRIM_START_WHITESPACE)
-                {
-                    matched_feature = matched_feature.union(FormatFeature::TRIM_START_WHITESPACE);
-                    continue;
-                }
-            }
-
-            if char == CR {
-                if feature.contains(FormatFeature::TRIM_CR) {
-                    matched_feature = matched_feature.union(FormatFeature::TRIM_CR);
-                    continue;
-                }
-            }
-
-            deque.push_back(char);
+    fn apply(&self, text: &mut String) -> bool {
+        let trimmed = match self {
+            TrimStart::Lf => text.trim_start_matches(LF),
+            TrimStart::Whitespace => text.trim_start(),
+        };
+        let len = text.len() - trimmed.len();
+        if len == 0 {
+            return false;
         }
-        loop {
-            let byte = match deque.back() {
-                Some(b) => *b,
-                None => break,
-            };
-            if byte == LF && feature.contains(FormatFeature::TRIM_END_LF) {
-                matched_feature = matched_feature.union(FormatFeature::TRIM_END_LF);
-                deque.pop_back();
-            } else if byte.is_whitespace() && feature.contains(FormatFeature::TRIM_END_WHITESPACE) {
-                matched_feature = matched_feature.union(FormatFeature::TRIM_END_WHITESPACE);
-                deque.pop_back();
-            } else {
-                break;
-            }
+        text.drain(..len);
+        true
+    }
+}
+
+/// Removes trailing `<LF>`s or trailing whitespace.
+#[derive(Debug, Clone, Copy)]
+pub enum TrimEnd {
+    Lf,
+    Whitespace,
+}
+
+impl TrimEnd {
+    pub const LF: &'static str = "trim-end-lf";
+    pub const WHITESPACE: &'static str = "trim-end-whitespace";
+}
+
+impl FormatRule for TrimEnd {
+    fn name(&self) -> &str {
+        match self {
+            TrimEnd::Lf => Self::LF,
+            TrimEnd::Whitespace => Self::WHITESPACE,
         }
-        if self.ends_with_zero {
-            deque.push_back('\0');
+    }
+
+    fn apply(&self, text: &mut String) -> bool {
+        let len = match self {
+            TrimEnd::Lf => text.trim_end_matches(LF).len(),
+            TrimEnd::Whitespace => text.trim_end().len(),
+        };
+        if len == text.len() {
+            return false;
         }
-        Ok(FormatResult::new(String::from_iter(deque), matched_feature))
+        text.truncate(len);
+        true
     }
 }
 
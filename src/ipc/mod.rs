@@ -0,0 +1,366 @@
+//! Control protocol between `clipd <command>` and a running service.
+//!
+//! Every message is one frame: a version byte, a big-endian `u32` payload length and a JSON
+//! payload.
+
+#[cfg(unix)]
+mod unix;
+
+#[cfg(unix)]
+pub use unix::*;
+
+use std::{
+    fmt,
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MAX_FRAME_LEN: u32 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Pause,
+    Resume,
+    Status,
+    Stop,
+    Reload,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(Status),
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Stopped,
+    StartPending,
+    StopPending,
+    Running,
+    ContinuePending,
+    PausePending,
+    Paused,
+}
+
+/// Reported by `clipd status`, the fields other than `state` are only known while the service
+/// answers on the control channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    pub state: State,
+    pub pid: Option<u32>,
+    pub uptime_secs: Option<u64>,
+    pub config_path: Option<PathBuf>,
+    /// Active rules in pipeline order.
+    pub rules: Vec<RuleStats>,
+    /// Connection to the display server, for backends that can lose it.
+    pub display: Option<DisplayStatus>,
+    /// Set while rewrites are held back because another program keeps undoing them.
+    pub ping_pong: Option<PingPongStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplayStatus {
+    /// `$DISPLAY` the service connects to.
+    pub name: Option<String>,
+    pub connected: bool,
+    /// Failed attempts to connect since the connection was lost.
+    pub attempts: u32,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PingPongStatus {
+    pub selection: String,
+    /// Seconds until rewrites are tried again.
+    pub resume_in_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleStats {
+    pub name: String,
+    /// Clipboard texts the rule was applied to.
+    pub seen: u64,
+    /// Clipboard texts the rule changed.
+    pub rewritten: u64,
+}
+
+impl Status {
+    pub fn new(state: State) -> Self {
+        Self {
+            state,
+            pid: None,
+            uptime_secs: None,
+            config_path: None,
+            rules: vec![],
+            display: None,
+            ping_pong: None,
+        }
+    }
+}
+
+impl fmt::Display for DisplayStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name.as_deref().unwrap_or("(unset)"))?;
+        if self.connected {
+            return write!(f, " (connected)");
+        }
+        write!(f, " (reconnecting, {} failed attempts", self.attempts)?;
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "State: {:?}", self.state)?;
+        if let Some(pid) = self.pid {
+            writeln!(f, "PID: {}", pid)?;
+        }
+        if let Some(secs) = self.uptime_secs {
+            writeln!(
+                f,
+                "Uptime: {}h {}m {}s",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60
+            )?;
+        }
+        if let Some(path) = &self.config_path {
+            writeln!(f, "Config: {}", path.display())?;
+        }
+        if let Some(display) = &self.display {
+            writeln!(f, "Display: {}", display)?;
+        }
+        if let Some(ping_pong) = &self.ping_pong {
+            writeln!(
+                f,
+                "Ping-pong: {} keeps being rewritten by another program, retry in {}s",
+                ping_pong.selection, ping_pong.resume_in_secs
+            )?;
+        }
+        if !self.rules.is_empty() {
+            writeln!(f, "Rules:")?;
+            let width = self.rules.iter().map(|r| r.name.len()).max().unwrap_or(0);
+            for rule in &self.rules {
+                writeln!(
+                    f,
+                    "  {:width$}  seen {}, rewritten {}",
+                    rule.name,
+                    rule.seen,
+                    rule.rewritten,
+                    width = width
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Response {
+    pub fn error<E: std::fmt::Display>(e: E) -> Self {
+        Response::Error {
+            message: e.to_string(),
+        }
+    }
+
+    /// Turns [`Response::Error`] into `Err`.
+    pub fn ok(self) -> Result<Self> {
+        match self {
+            Response::Error { message } => bail!("{}", message),
+            r => Ok(r),
+        }
+    }
+}
+
+pub fn write_frame<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .context("Frame too large")?;
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(PROTOCOL_VERSION);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns `None` if the peer closed the connection before a new frame.
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut version = [0u8; 1];
+    if reader.read(&mut version)? == 0 {
+        return Ok(None);
+    }
+    if version[0] != PROTOCOL_VERSION {
+        bail!(
+            "Unsupported protocol version {}, expect {}",
+            version[0],
+            PROTOCOL_VERSION
+        )
+    }
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        bail!("Frame too large: {}", len)
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{
+        read_frame, write_frame, DisplayStatus, PingPongStatus, Request, Response, RuleStats,
+        State, Status, PROTOCOL_VERSION,
+    };
+
+    fn round_trip<T>(message: T)
+    where
+        T: serde::Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let mut buf = vec![];
+        write_frame(&mut buf, &message).unwrap();
+        assert_eq!(buf[0], PROTOCOL_VERSION);
+        assert_eq!(
+            u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize,
+            buf.len() - 5
+        );
+        let mut reader = buf.as_slice();
+        assert_eq!(read_frame::<_, T>(&mut reader).unwrap(), Some(message));
+        assert_eq!(read_frame::<_, T>(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn codec() {
+        for request in [
+            Request::Pause,
+            Request::Resume,
+            Request::Status,
+            Request::Stop,
+            Request::Reload,
+        ] {
+            round_trip(request);
+        }
+        round_trip(Response::Ok);
+        round_trip(Response::error("boom"));
+        round_trip(Response::Status(Status::new(State::Stopped)));
+        round_trip(Response::Status(Status {
+            state: State::Paused,
+            pid: Some(42),
+            uptime_secs: Some(3723),
+            config_path: Some(PathBuf::from("/etc/clipd.toml")),
+            rules: vec![RuleStats {
+                name: "trim-cr".into(),
+                seen: 3,
+                rewritten: 1,
+            }],
+            display: Some(DisplayStatus {
+                name: Some(":0".into()),
+                connected: false,
+                attempts: 2,
+                error: Some("Connection refused".into()),
+            }),
+            ping_pong: Some(PingPongStatus {
+                selection: "clipboard".into(),
+                resume_in_secs: 12,
+            }),
+        }));
+
+        let mut buf = vec![];
+        write_frame(&mut buf, &Request::Pause).unwrap();
+        assert_eq!(&buf[5..], br#"{"type":"pause"}"#);
+    }
+
+    #[test]
+    fn bad_frames() {
+        let mut buf = vec![];
+        write_frame(&mut buf, &Request::Stop).unwrap();
+
+        let mut wrong_version = buf.clone();
+        wrong_version[0] = PROTOCOL_VERSION + 1;
+        assert!(read_frame::<_, Request>(&mut wrong_version.as_slice()).is_err());
+
+        let truncated = &buf[..buf.len() - 1];
+        assert!(read_frame::<_, Request>(&mut &truncated[..]).is_err());
+
+        let too_large = [PROTOCOL_VERSION, 0xff, 0xff, 0xff, 0xff];
+        assert!(read_frame::<_, Request>(&mut &too_large[..]).is_err());
+
+        assert!(Response::error("boom").ok().is_err());
+        assert_eq!(Response::Ok.ok().unwrap(), Response::Ok);
+    }
+
+    #[test]
+    fn status_text() {
+        assert_eq!(Status::new(State::Stopped).to_string(), "State: Stopped\n");
+        let status = Status {
+            state: State::Running,
+            pid: Some(42),
+            uptime_secs: Some(3723),
+            config_path: Some(PathBuf::from("/etc/clipd.toml")),
+            rules: vec![
+                RuleStats {
+                    name: "trim-cr".into(),
+                    seen: 3,
+                    rewritten: 1,
+                },
+                RuleStats {
+                    name: "trim-end-lf".into(),
+                    seen: 3,
+                    rewritten: 0,
+                },
+            ],
+            display: Some(DisplayStatus {
+                name: Some(":0".into()),
+                connected: true,
+                attempts: 0,
+                error: None,
+            }),
+            ping_pong: Some(PingPongStatus {
+                selection: "clipboard".into(),
+                resume_in_secs: 12,
+            }),
+        };
+        assert_eq!(
+            status.to_string(),
+            "State: Running\n\
+             PID: 42\n\
+             Uptime: 1h 2m 3s\n\
+             Config: /etc/clipd.toml\n\
+             Display: :0 (connected)\n\
+             Ping-pong: clipboard keeps being rewritten by another program, retry in 12s\n\
+             Rules:\n\
+             \x20 trim-cr      seen 3, rewritten 1\n\
+             \x20 trim-end-lf  seen 3, rewritten 0\n"
+        );
+
+        let display = DisplayStatus {
+            name: Some(":1".into()),
+            connected: false,
+            attempts: 3,
+            error: Some("Connection refused".into()),
+        };
+        assert_eq!(
+            display.to_string(),
+            ":1 (reconnecting, 3 failed attempts: Connection refused)"
+        );
+    }
+}
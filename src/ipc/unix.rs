@@ -0,0 +1,138 @@
+use std::{
+    io,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+
+use super::{read_frame, write_frame, Request, Response};
+use crate::{util::dirs, SERVICE_NAME};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn socket_path() -> PathBuf {
+    dirs::runtime_dir().join(format!("{}.sock", SERVICE_NAME))
+}
+
+/// Removes the socket file when dropped.
+#[derive(Debug)]
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Serves every connection on its own thread, `handler` is called once per request.
+    ///
+    /// An existing socket file is replaced, the caller must make sure no other instance is
+    /// serving on it.
+    pub fn listen<F>(path: PathBuf, handler: F) -> Result<Self>
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("remove {:?}", path))
+            }
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path).with_context(|| format!("bind {:?}", path))?;
+        log::debug!("Control socket: {:?}", path);
+
+        let handler = Arc::new(handler);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::error!("Accept control connection failed: {:?}", e);
+                        continue;
+                    }
+                };
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = serve(stream, handler.as_ref()) {
+                        log::warn!("Control connection error: {:?}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        log::debug!("Remove control socket: {:?}", self.path);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve<F: Fn(Request) -> Response>(mut stream: UnixStream, handler: &F) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    while let Some(request) = read_frame::<_, Request>(&mut stream)? {
+        log::debug!("Control request: {:?}", request);
+        let response = handler(request);
+        log::trace!("Control response: {:?}", response);
+        write_frame(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+/// Sends one request to the service listening on `path`.
+pub fn request(path: &Path, request: Request) -> Result<Response> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("{} is not running, connect {:?}", SERVICE_NAME, path))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write_frame(&mut stream, &request)?;
+    read_frame(&mut stream)?.context("Connection closed by service")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::{request, ControlServer};
+    use crate::ipc::{Request, Response, State, Status};
+
+    #[test]
+    fn request_response() {
+        let path = std::env::temp_dir().join(format!("clipd-ipc-{}.sock", std::process::id()));
+        assert!(request(&path, Request::Status).is_err());
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let server_paused = paused.clone();
+        let server = ControlServer::listen(path.clone(), move |request| match request {
+            Request::Pause => {
+                server_paused.store(true, Ordering::SeqCst);
+                Response::Ok
+            }
+            Request::Status => {
+                Response::Status(Status::new(match server_paused.load(Ordering::SeqCst) {
+                    true => State::Paused,
+                    false => State::Running,
+                }))
+            }
+            _ => Response::error("unsupported"),
+        })
+        .unwrap();
+
+        assert_eq!(request(&path, Request::Pause).unwrap(), Response::Ok);
+        assert!(paused.load(Ordering::SeqCst));
+        assert_eq!(
+            request(&path, Request::Status).unwrap(),
+            Response::Status(Status::new(State::Paused))
+        );
+        assert!(request(&path, Request::Stop).unwrap().ok().is_err());
+
+        drop(server);
+        assert!(!path.exists());
+    }
+}
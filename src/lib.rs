@@ -1,10 +1,14 @@
 use std::ffi::OsString;
 
 use anyhow::{Context, Result};
+use config::Config;
 
+pub mod config;
 mod data;
+pub mod engine;
 mod error;
 pub mod fmt;
+pub mod ipc;
 mod os;
 mod util;
 
@@ -19,7 +23,7 @@ pub use util::*;
 #[macro_use]
 extern crate objc;
 
-pub const SERVICE_NAME: &'static str = "clipd";
+pub const SERVICE_NAME: &str = "clipd";
 
 pub fn run(args: Args) -> Result<()> {
     if args.version {
@@ -27,14 +31,18 @@ pub fn run(args: Args) -> Result<()> {
         std::process::exit(0)
     }
 
-    let _guard = init_log(&args)?;
+    let config = Config::load(args.config.as_deref())?;
+    let _guard = init_log(&args, &config)?;
     for line in format_version().lines() {
         log::debug!("======== {}", line);
     }
 
-    let oal = OAL::init(&args)?;
-
     log::debug!("args: {:?}", args);
+    log::debug!("config: {:?}", config);
+
+    // Absolute, the service does not start in this directory.
+    let config_path = args.config.as_ref().and(config.path.clone());
+    let oal = OAL::init(&args, config)?;
 
     let sub_cmd = args
         .sub
@@ -51,7 +59,11 @@ pub fn run(args: Args) -> Result<()> {
     let controller = oal.service_controller()?;
     match sub_cmd {
         SubCommand::Install => {
-            controller.install(vec![OsString::from("run"), OsString::from("--daemon")])?
+            let mut arguments = vec![OsString::from("run"), OsString::from("--daemon")];
+            if let Some(path) = config_path {
+                arguments.extend([OsString::from("--config"), path.into_os_string()]);
+            }
+            controller.install(arguments)?
         }
         SubCommand::Run(_) => panic!(),
         SubCommand::Start => controller.start(vec![])?,
@@ -59,18 +71,27 @@ pub fn run(args: Args) -> Result<()> {
         SubCommand::Resume => controller.resume()?,
         SubCommand::Stop => controller.stop()?,
         SubCommand::Restart => controller.restart(vec![])?,
-        SubCommand::Status => controller.status()?,
+        SubCommand::Status(args) => {
+            let status = controller.status()?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                print!("{}", status);
+            }
+        }
+        SubCommand::Reload => controller.reload()?,
         SubCommand::Uninstall(_) => controller.uninstall()?,
     }
     Ok(())
 }
 
-fn init_log(args: &Args) -> Result<tracing_appender::non_blocking::WorkerGuard> {
-    let level = if cfg!(debug_assertions) {
+fn init_log(args: &Args, config: &Config) -> Result<tracing_appender::non_blocking::WorkerGuard> {
+    let level = config.log.level.unwrap_or(if cfg!(debug_assertions) {
         log::Level::Trace
     } else {
         log::Level::Warn
-    };
+    });
+    let log_dir = config.log.dir.clone().unwrap_or_else(std::env::temp_dir);
     let log_file_name = {
         let daemon = if let Some(SubCommand::Run(args)) = &args.sub {
             args.daemon
@@ -91,7 +112,7 @@ fn init_log(args: &Args) -> Result<tracing_appender::non_blocking::WorkerGuard>
             format!("{}.log", name)
         }
     };
-    Ok(logger::start_tracing(level, &log_file_name).context("init logger")?)
+    logger::start_tracing(level, &log_dir, &log_file_name).context("init logger")
 }
 
 fn format_version() -> String {
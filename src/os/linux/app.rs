@@ -0,0 +1,86 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use x11_clipboard::{Atom, Window};
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{AtomEnum, ConnectionExt},
+};
+
+use crate::config::App;
+
+/// How far up the window tree [`AppResolver::app_of`] looks for a window with `WM_CLASS` or
+/// `_NET_WM_PID`.
+const MAX_DEPTH: usize = 8;
+
+/// Finds the application of a window, with the atoms it needs interned once.
+#[derive(Debug, Clone, Copy)]
+pub struct AppResolver {
+    net_wm_pid: Atom,
+    client_leader: Atom,
+}
+
+impl AppResolver {
+    pub fn new(connection: &impl Connection) -> Result<Self> {
+        let net_wm_pid = connection.intern_atom(false, b"_NET_WM_PID")?;
+        let client_leader = connection.intern_atom(false, b"WM_CLIENT_LEADER")?;
+        Ok(Self {
+            net_wm_pid: net_wm_pid.reply()?.atom,
+            client_leader: client_leader.reply()?.atom,
+        })
+    }
+
+    /// Resolves the application `window` belongs to. Requestors and selection owners are often
+    /// unmapped helper windows, so their parents and client leader are tried as well.
+    pub fn app_of(&self, connection: &impl Connection, window: Window) -> Result<App> {
+        let root = connection
+            .setup()
+            .roots
+            .iter()
+            .map(|s| s.root)
+            .collect::<Vec<_>>();
+
+        let mut candidates = vec![window];
+        let mut app = App::default();
+        for _ in 0..MAX_DEPTH {
+            let window = match candidates.pop() {
+                Some(w) if w != x11rb::NONE && !root.contains(&w) => w,
+                _ => break,
+            };
+            let class = connection
+                .get_property(false, window, AtomEnum::WM_CLASS, AtomEnum::STRING, 0, 256)?
+                .reply()?;
+            app.class = class
+                .value
+                .split(|b| *b == 0)
+                .filter(|name| !name.is_empty())
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .collect();
+            let pid = connection
+                .get_property(false, window, self.net_wm_pid, AtomEnum::CARDINAL, 0, 1)?
+                .reply()?
+                .value32()
+                .and_then(|mut v| v.next());
+            app.exe = pid.and_then(exe_of);
+            if !app.class.is_empty() || app.exe.is_some() {
+                break;
+            }
+
+            let leader = connection
+                .get_property(false, window, self.client_leader, AtomEnum::WINDOW, 0, 1)?
+                .reply()?
+                .value32()
+                .and_then(|mut v| v.next());
+            candidates.push(connection.query_tree(window)?.reply()?.parent);
+            if let Some(leader) = leader.filter(|l| *l != window) {
+                candidates.push(leader);
+            }
+        }
+        Ok(app)
+    }
+}
+
+/// The executable of `pid`, if it runs on this machine and is ours to look at.
+fn exe_of(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
@@ -0,0 +1,83 @@
+use std::time::Instant;
+
+use anyhow::Result;
+
+use super::clipboard::X11Clipboard;
+use crate::{
+    config::{App, Selection},
+    engine::{ChangeFilter, ClipboardBackend, WaitingBackend},
+};
+
+/// [`X11Clipboard`] for the engine. The owner of a change is looked at once it settled, before
+/// anything is converted.
+pub struct X11Backend {
+    clipboard: X11Clipboard,
+    filter: Box<dyn ChangeFilter>,
+}
+
+impl X11Backend {
+    pub fn new(clipboard: X11Clipboard, filter: Box<dyn ChangeFilter>) -> Self {
+        Self { clipboard, filter }
+    }
+
+    pub fn clipboard(&self) -> &X11Clipboard {
+        &self.clipboard
+    }
+
+    /// Continues on a new connection, see [`X11Clipboard::reconnect`].
+    pub fn set_clipboard(&mut self, clipboard: X11Clipboard) {
+        self.clipboard = clipboard;
+    }
+}
+
+impl ClipboardBackend for X11Backend {
+    fn read_text(&mut self, selection: Selection) -> Result<Option<String>> {
+        self.clipboard.read_changed(selection, &*self.filter)
+    }
+
+    fn write_text(&mut self, selection: Selection, text: String) -> Result<bool> {
+        self.clipboard.replace_text(selection, text)
+    }
+
+    /// Changes by our own window are never reported.
+    fn echoes_writes(&self) -> bool {
+        false
+    }
+
+    fn read_owner(&self, selection: Selection) -> Option<String> {
+        self.clipboard.read_from(selection)
+    }
+
+    fn is_unchanged(&mut self, selection: Selection) -> Result<Option<bool>> {
+        self.clipboard.is_unchanged(selection)
+    }
+
+    fn defer_text<F>(&mut self, selection: Selection, text: String, format: F) -> Result<bool>
+    where
+        F: Fn(&App) -> String + Send + Sync + 'static,
+    {
+        self.clipboard.defer_text(selection, text, format)
+    }
+
+    fn save(&mut self, selection: Selection, text: &str) -> Result<()> {
+        self.clipboard.save(selection, text.to_owned())
+    }
+
+    fn store_text(&mut self, selection: Selection, text: String) -> Result<()> {
+        self.clipboard.store_utf8_string(selection, text)
+    }
+
+    fn read_current(&mut self, selection: Selection) -> Result<Option<String>> {
+        self.clipboard.read_utf8_string_now(selection)
+    }
+
+    fn is_selecting(&self) -> Result<bool> {
+        self.clipboard.is_pointer_pressed()
+    }
+}
+
+impl WaitingBackend for X11Backend {
+    fn wait_for_change(&mut self, deadline: Option<Instant>) -> Result<Option<Selection>> {
+        self.clipboard.wait_change(&*self.filter, deadline)
+    }
+}
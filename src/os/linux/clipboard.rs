@@ -1,136 +1,1673 @@
-use anyhow::Result;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use x11_clipboard::{error::Error, Atom, Clipboard};
+use anyhow::Result;
+use encoding_rs::Encoding;
+use x11_clipboard::{error::Error, Atom, Context, Window};
 use x11rb::{
     connection::Connection,
+    errors::{ConnectionError, ReplyError, ReplyOrIdError},
     protocol::{
         xfixes,
-        xproto::{AtomEnum, ConnectionExt},
+        xproto::{
+            AtomEnum, ConnectionExt, EventMask, KeyButMask, PropMode, Property,
+            SelectionNotifyEvent, SelectionRequestEvent, Timestamp, SELECTION_NOTIFY_EVENT,
+        },
         Event,
     },
     rust_connection::ConnectError,
+    wrapper::ConnectionExt as _,
+};
+
+use super::{
+    app::AppResolver,
+    display::Display,
+    owner::{Contents, PropertyValue, Render, Renders, SelectionOwner},
+    text::{self, TextType},
 };
+use crate::{
+    config::{App, Selection},
+    engine::ChangeFilter,
+};
+
+/// Interrupts [`X11Clipboard::wait_change`] and [`X11Clipboard::sleep`] from another thread,
+/// and drops what was rendered for pastes so far.
+#[derive(Debug, Clone)]
+pub struct Waker {
+    fd: Arc<OwnedFd>,
+    renders: Renders,
+}
+
+impl Waker {
+    /// A waker and the end of its pipe that it wakes.
+    pub fn pipe() -> io::Result<(OwnedFd, Self)> {
+        let (rx, tx) = pipe()?;
+        let waker = Self {
+            fd: Arc::new(tx),
+            renders: Renders::default(),
+        };
+        Ok((rx, waker))
+    }
+
+    /// Pastes are rendered again, as what they are rendered with changed.
+    pub fn invalidate_renders(&self) {
+        self.renders.invalidate();
+    }
+
+    pub fn wake(&self) {
+        let byte = 1u8;
+        // The pipe is non-blocking, a full pipe already has a pending wake up.
+        unsafe { libc::write(self.fd.as_raw_fd(), &byte as *const u8 as *const _, 1) };
+    }
+}
+
+/// How long a conversion waits for the owner, and the longest pause between two chunks of an
+/// `INCR` transfer.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Conversions that timed out whose property is kept for a late reply, the oldest is reused
+/// beyond this.
+const MAX_ABANDONED: usize = 4;
+
+/// How long keeping a copy for after its owner exits may hold up the next change or the owner
+/// handing it over, and how much of it is kept. The text is always kept, other targets as far as
+/// these allow.
+const SAVE_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_SAVED_SIZE: usize = 16 << 20;
+
+/// Targets about the selection rather than its data, never copied from another owner.
+const META_TARGETS: [&str; 6] = [
+    "TARGETS",
+    "MULTIPLE",
+    "TIMESTAMP",
+    "DELETE",
+    "SAVE_TARGETS",
+    "INSERT_SELECTION",
+];
+
+/// The text targets we serve.
+#[derive(Debug, Clone, Copy)]
+struct TextAtoms {
+    utf8_string: Atom,
+    string: Atom,
+    compound_text: Atom,
+    text: Atom,
+    mime_plain: Atom,
+    mime_utf8: Atom,
+    /// The text as copied, while the text targets are formatted on paste.
+    original: Atom,
+}
+
+impl TextAtoms {
+    fn all(&self) -> Vec<Atom> {
+        vec![
+            self.utf8_string,
+            self.mime_utf8,
+            self.mime_plain,
+            self.compound_text,
+            self.text,
+            self.string,
+        ]
+    }
+
+    /// `value` in every text target, `STRING` only if the encoding holds all of it.
+    fn contents(&self, value: String, string_encoding: Option<&'static Encoding>) -> Contents {
+        let contents = Contents::default()
+            .with(self.utf8_string, value.clone().into_bytes())
+            .with(self.mime_utf8, value.clone().into_bytes())
+            // UTF-8 in practice, whatever the MIME type says.
+            .with(self.mime_plain, value.clone().into_bytes())
+            .with(self.compound_text, text::encode_compound_text(&value));
+        // `TEXT` is answered with the most compatible encoding that holds all of it.
+        match text::encode_string(&value, string_encoding) {
+            Some(string) => contents
+                .with_type(self.text, self.string, string.clone())
+                .with(self.string, string),
+            None => contents.with_type(self.text, self.utf8_string, value.into_bytes()),
+        }
+    }
+}
+
+/// Atoms of the freedesktop clipboard manager protocol.
+#[derive(Debug, Clone, Copy)]
+struct ManagerAtoms {
+    clipboard_manager: Atom,
+    save_targets: Atom,
+    null: Atom,
+}
+
+/// Properties of our window that conversions are received in, each is used by one conversion
+/// at a time. One whose conversion timed out is only used again once the late reply is
+/// drained, so that the reply is never taken for that of another conversion.
+#[derive(Debug, Default)]
+struct Properties {
+    free: Vec<Atom>,
+    abandoned: VecDeque<Atom>,
+    interned: usize,
+}
+
+/// Limits on copying the targets of an owner.
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    deadline: Instant,
+    size: usize,
+}
+
+impl Budget {
+    /// For keeping a copy, see [`SAVE_TIMEOUT`].
+    fn save() -> Self {
+        Self {
+            deadline: Instant::now() + SAVE_TIMEOUT,
+            size: MAX_SAVED_SIZE,
+        }
+    }
+}
+
+/// Who owns `CLIPBOARD_MANAGER`, as far as we know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Manager {
+    /// Not looked at since it last changed.
+    Unknown,
+    Us,
+    Other,
+}
+
+/// A new owner of a selection, as reported.
+#[derive(Debug, Clone, Copy)]
+struct Change {
+    owner: Window,
+    /// When the owner got the selection.
+    owned_at: Timestamp,
+    /// When the change was reported, the selection is converted as of then.
+    time: Timestamp,
+}
+
+/// The targets besides plain text an owner offered when it was read.
+struct Offer {
+    targets: Vec<Atom>,
+    time: Timestamp,
+}
 
 pub struct X11Clipboard {
-    clipboard: Clipboard,
+    getter: Context,
+    owner: SelectionOwner,
+    wake_rx: Arc<OwnedFd>,
+    waker: Waker,
+    text_atoms: TextAtoms,
+    string_encoding: Cell<Option<&'static Encoding>>,
+    atom_names: RefCell<HashMap<Atom, String>>,
+    offers: RefCell<HashMap<Atom, Offer>>,
+    apps: AppResolver,
+    properties: RefCell<Properties>,
+    manager_atoms: ManagerAtoms,
+    /// Whether we want to be the clipboard manager.
+    manager: Cell<bool>,
+    /// Who runs the clipboard manager.
+    manager_owner: Cell<Manager>,
+    /// Copies served once their owner is gone, by selection.
+    saved: RefCell<HashMap<Atom, Contents>>,
+    /// Events received while waiting for a specific one.
+    queue: RefCell<VecDeque<Event>>,
+    /// When each selection got its latest owner, as far as events arrived.
+    owned_at: RefCell<HashMap<Atom, Timestamp>>,
+    /// When the owner we read each selection from got it.
+    read_at: RefCell<HashMap<Atom, Timestamp>>,
+    /// The name of the app we read each selection from, if it could be told.
+    read_from: RefCell<HashMap<Atom, String>>,
+    /// The change of each selection that is not read yet, `None` if it changed again since to
+    /// something that is not read.
+    changed: RefCell<HashMap<Atom, Option<Change>>>,
 }
 
 impl X11Clipboard {
     pub fn new() -> Result<Self> {
-        let clipboard = Clipboard::new()?;
-        log::debug!("atoms: {:?}", clipboard.getter.atoms);
-        xfixes::query_version(&clipboard.getter.connection, 5, 0)?;
-        Ok(Self { clipboard })
+        Self::connect(None)
+    }
+
+    /// Connects to `display`, `$DISPLAY` if `None`.
+    pub fn connect(display: Option<&str>) -> Result<Self> {
+        let (wake_rx, waker) = Waker::pipe()?;
+        Self::open(&Display::named(display), Arc::new(wake_rx), waker)
+    }
+
+    /// Connects again to `display` after this connection was lost. The new one is woken by the
+    /// same [`Waker`], but copies saved on the old one are gone.
+    pub fn reconnect(&self, display: &Display) -> Result<Self> {
+        let clipboard = Self::open(display, self.wake_rx.clone(), self.waker.clone())?;
+        clipboard.set_string_encoding(self.string_encoding.get());
+        Ok(clipboard)
+    }
+
+    fn open(display: &Display, wake_rx: Arc<OwnedFd>, waker: Waker) -> Result<Self> {
+        let getter = display.context()?;
+        log::debug!("atoms: {:?}", getter.atoms);
+        let owner = SelectionOwner::new(display)?;
+        let connection = &getter.connection;
+        let cookies = [
+            connection.intern_atom(false, b"COMPOUND_TEXT")?,
+            connection.intern_atom(false, b"TEXT")?,
+            connection.intern_atom(false, b"text/plain")?,
+            connection.intern_atom(false, b"text/plain;charset=utf-8")?,
+            connection.intern_atom(false, b"CLIPD_ORIGINAL")?,
+            connection.intern_atom(false, b"CLIPBOARD_MANAGER")?,
+            connection.intern_atom(false, b"SAVE_TARGETS")?,
+            connection.intern_atom(false, b"NULL")?,
+        ];
+        let [compound_text, text, mime_plain, mime_utf8, original, manager, save_targets, null] =
+            cookies.map(|c| c.reply().map(|r| r.atom));
+        let text_atoms = TextAtoms {
+            utf8_string: getter.atoms.utf8_string,
+            string: getter.atoms.string,
+            compound_text: compound_text?,
+            text: text?,
+            mime_plain: mime_plain?,
+            mime_utf8: mime_utf8?,
+            original: original?,
+        };
+        let manager_atoms = ManagerAtoms {
+            clipboard_manager: manager?,
+            save_targets: save_targets?,
+            null: null?,
+        };
+        let apps = AppResolver::new(connection)?;
+        let clipboard = Self {
+            getter,
+            owner,
+            wake_rx,
+            waker,
+            text_atoms,
+            string_encoding: Cell::new(None),
+            atom_names: Default::default(),
+            offers: Default::default(),
+            apps,
+            properties: Default::default(),
+            manager_atoms,
+            manager: Cell::new(false),
+            manager_owner: Cell::new(Manager::Unknown),
+            saved: Default::default(),
+            queue: Default::default(),
+            owned_at: Default::default(),
+            read_at: Default::default(),
+            read_from: Default::default(),
+            changed: Default::default(),
+        };
+        clipboard.select_input()?;
+        Ok(clipboard)
+    }
+
+    pub fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    /// Waits for `timeout` without touching the connection, `true` if woken by
+    /// [`Waker::wake`] before.
+    pub fn sleep(&self, timeout: Duration) -> bool {
+        let mut fd = libc::pollfd {
+            fd: self.wake_rx.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        if unsafe { libc::poll(&mut fd, 1, timeout) } <= 0 {
+            return false;
+        }
+        self.drain_wake();
+        true
     }
 
-    pub fn wait_utf8_string(&self) -> Result<String> {
-        let context = &self.clipboard.getter;
-        let atoms = &context.atoms;
+    fn drain_wake(&self) {
+        let mut buffer = [0u8; 64];
+        while unsafe {
+            libc::read(
+                self.wake_rx.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut _,
+                buffer.len(),
+            )
+        } > 0
+        {}
+    }
+
+    /// Encoding of `STRING` read and written from now on, Latin-1 if `None`.
+    pub fn set_string_encoding(&self, encoding: Option<&'static Encoding>) {
+        self.string_encoding.set(encoding);
+    }
+
+    fn atom(&self, selection: Selection) -> Atom {
+        let atoms = &self.getter.atoms;
+        match selection {
+            Selection::Clipboard => atoms.clipboard,
+            Selection::Primary => atoms.primary,
+            Selection::Secondary => AtomEnum::SECONDARY.into(),
+        }
+    }
+
+    fn selection(&self, atom: Atom) -> Option<Selection> {
+        Selection::ALL.into_iter().find(|s| self.atom(*s) == atom)
+    }
+
+    /// Asks for an event whenever any selection, or the clipboard manager, changes owner.
+    fn select_input(&self) -> Result<()> {
+        let context = &self.getter;
         let connection = &context.connection;
+        xfixes::query_version(connection, 5, 0)?;
 
         let screen = connection
             .setup()
             .roots
             .get(context.screen)
             .ok_or(Error::XcbConnect(ConnectError::InvalidScreen))?;
+        let selections = Selection::ALL.map(|s| self.atom(s));
+        for selection in selections
+            .into_iter()
+            .chain([self.manager_atoms.clipboard_manager])
+        {
+            xfixes::select_selection_input(
+                connection,
+                screen.root,
+                selection,
+                xfixes::SelectionEventMask::SET_SELECTION_OWNER
+                    | xfixes::SelectionEventMask::SELECTION_WINDOW_DESTROY
+                    | xfixes::SelectionEventMask::SELECTION_CLIENT_CLOSE,
+            )?
+            .check()?;
+        }
+        Ok(())
+    }
 
-        let cookie = xfixes::select_selection_input(
-            connection,
-            screen.root,
-            atoms.clipboard,
-            xfixes::SelectionEventMask::SET_SELECTION_OWNER,
-        )?;
-        let sequence_number = cookie.sequence_number();
-        log::trace!("sequence number: {:?}", sequence_number);
-        cookie.check()?;
-
+    /// Waits for a change and reads it, see [`X11Clipboard::wait_change`].
+    #[cfg(test)]
+    pub fn wait_utf8_string<F>(
+        &self,
+        filter: F,
+        deadline: Option<Instant>,
+    ) -> Result<Option<(Selection, String)>>
+    where
+        F: ChangeFilter,
+    {
         loop {
-            let text = match self.read_utf8_string(sequence_number)? {
-                Some(t) => t,
+            let selection = match self.wait_change(&filter, deadline)? {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            if let Some(text) = self.read_changed(selection, &filter)? {
+                return Ok(Some((selection, text)));
+            }
+        }
+    }
+
+    /// Reads the text `selection` changed to as seen by [`X11Clipboard::wait_change`], or its
+    /// current text if no change is waiting. `None` if it has no text, `filter` does not accept
+    /// its owner, or it changed again since to something that is not read.
+    ///
+    /// The owner is only looked up here, once for a burst of changes.
+    pub fn read_changed<F>(&self, selection: Selection, filter: &F) -> Result<Option<String>>
+    where
+        F: ChangeFilter + ?Sized,
+    {
+        let atom = self.atom(selection);
+        let changed = self.changed.borrow_mut().remove(&atom);
+        match changed {
+            Some(Some(change)) => {
+                let owner = self.app_of(change.owner);
+                self.read_from.borrow_mut().remove(&atom);
+                if !filter.accepts(selection, owner.as_ref()) {
+                    self.read_at.borrow_mut().remove(&atom);
+                    return Ok(None);
+                }
+                self.read_at.borrow_mut().insert(atom, change.owned_at);
+                if let Some(name) = owner.and_then(|a| a.name()) {
+                    self.read_from.borrow_mut().insert(atom, name);
+                }
+                self.read_text(atom, change.time)
+            }
+            Some(None) => {
+                log::debug!("{} changed again, not read", selection.name());
+                Ok(None)
+            }
+            None => self.read_utf8_string_now(selection),
+        }
+    }
+
+    /// Reads the current text of `selection`, `None` if it has no owner or no text.
+    pub fn read_utf8_string_now(&self, selection: Selection) -> Result<Option<String>> {
+        let selection = self.atom(selection);
+        // Which owner answers is not known.
+        self.read_at.borrow_mut().remove(&selection);
+        self.read_from.borrow_mut().remove(&selection);
+        self.read_text(selection, x11rb::CURRENT_TIME)
+    }
+
+    /// The name of the app `selection` was last read from by [`X11Clipboard::read_changed`],
+    /// `None` if it could not be told or it was read otherwise since.
+    pub fn read_from(&self, selection: Selection) -> Option<String> {
+        self.read_from.borrow().get(&self.atom(selection)).cloned()
+    }
+
+    /// Whether `selection` has the same owner as when it was last read by
+    /// [`X11Clipboard::read_changed`], `None` if it was read otherwise since.
+    pub fn is_unchanged(&self, selection: Selection) -> Result<Option<bool>> {
+        let selection = self.atom(selection);
+        let read_at = match self.read_at.borrow().get(&selection) {
+            Some(t) => *t,
+            None => return Ok(None),
+        };
+        // Events are sent before the reply, so every change up to now has arrived after it.
+        let connection = &self.getter.connection;
+        connection.get_selection_owner(selection)?.reply()?;
+        while let Some(event) = connection.poll_for_event()? {
+            self.received(&event);
+            self.queue.borrow_mut().push_back(event);
+        }
+        Ok(Some(
+            self.owned_at.borrow().get(&selection) == Some(&read_at),
+        ))
+    }
+
+    fn received(&self, event: &Event) {
+        if let Event::XfixesSelectionNotify(e) = event {
+            if e.subtype == xfixes::SelectionEvent::SET_SELECTION_OWNER {
+                self.owned_at
+                    .borrow_mut()
+                    .insert(e.selection, e.selection_timestamp);
+            }
+        }
+    }
+
+    /// Reads `selection` as the best text target its owner offers.
+    fn read_text(&self, selection: Atom, time: Timestamp) -> Result<Option<String>> {
+        let atoms = &self.getter.atoms;
+        let targets = self.targets(selection, time, None)?;
+        let names = self.atom_names(&targets)?;
+        let offer = Offer {
+            targets: targets
+                .iter()
+                .zip(&names)
+                .filter(|(_, name)| {
+                    !text::is_plain_text(name) && !META_TARGETS.contains(&name.as_str())
+                })
+                .map(|(target, _)| *target)
+                .collect(),
+            time,
+        };
+        self.offers.borrow_mut().insert(selection, offer);
+
+        let candidates = if targets.is_empty() {
+            // Owners that do not answer `TARGETS` mostly speak one of these.
+            log::debug!("No TARGETS for {}", selection);
+            vec![atoms.utf8_string, atoms.string]
+        } else {
+            let best = text::best_target(targets.iter().zip(names.iter().map(String::as_str)));
+            match best {
+                Some((target, type_)) => {
+                    log::trace!("Read {} as {:?}", selection, type_);
+                    vec![*target]
+                }
+                None => {
+                    log::debug!("No text target for {}: {:?}", selection, names);
+                    return Ok(None);
+                }
+            }
+        };
+
+        for target in candidates {
+            let value = match self.convert(selection, target, time)? {
+                Some(v) => v,
                 None => continue,
             };
+            let name = self.atom_names(&[value.type_])?.remove(0);
+            return Ok(match TextType::from_name(&name) {
+                Some(t) => Some(t.decode(&value.data, self.string_encoding.get())),
+                None => {
+                    log::trace!("Ignore unexpected type: {:?}", name);
+                    None
+                }
+            });
+        }
+        Ok(None)
+    }
 
-            connection
-                .delete_property(context.window, atoms.property)?
-                .check()?;
+    /// The targets the owner of `selection` offers, empty if it does not answer `TARGETS`.
+    fn targets(
+        &self,
+        selection: Atom,
+        time: Timestamp,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<Atom>> {
+        let targets = self.getter.atoms.targets;
+        Ok(
+            match self.convert_until(selection, targets, time, deadline)? {
+                Some(value) if value.type_ == Atom::from(AtomEnum::ATOM) => value
+                    .data
+                    .chunks_exact(4)
+                    .map(|c| u32::from_ne_bytes(c.try_into().unwrap()))
+                    .collect(),
+                _ => vec![],
+            },
+        )
+    }
 
-            if connection
-                .get_selection_owner(atoms.clipboard)?
-                .reply()
-                .map(|reply| reply.owner == context.window)
-                .unwrap_or(false)
-            {
-                continue;
+    /// Converts `selection` to `target` and reads the result. `None` if the owner refused or did
+    /// not answer in time.
+    fn convert(
+        &self,
+        selection: Atom,
+        target: Atom,
+        time: Timestamp,
+    ) -> Result<Option<PropertyValue>> {
+        self.convert_until(selection, target, time, None)
+    }
+
+    /// Like [`X11Clipboard::convert`], but gives up at `deadline` even if the owner still sends.
+    fn convert_until(
+        &self,
+        selection: Atom,
+        target: Atom,
+        time: Timestamp,
+        deadline: Option<Instant>,
+    ) -> Result<Option<PropertyValue>> {
+        let context = &self.getter;
+        let property = self.take_property()?;
+        context
+            .connection
+            .convert_selection(context.window, selection, target, property, time)?
+            .check()?;
+
+        let reply_deadline = wait_deadline(deadline);
+        let mut skipped = VecDeque::new();
+        // A late refusal of an earlier conversion can not be told apart, but carries no data.
+        let replied = loop {
+            let event = match self.wait_for_event(Some(reply_deadline)) {
+                Ok(Some(e)) => e,
+                Ok(None) => {
+                    log::warn!("Convert {} to {} timeout", selection, target);
+                    break Ok(None);
+                }
+                Err(e) => break Err(e),
+            };
+            match event {
+                Event::SelectionNotify(event)
+                    if event.selection == selection
+                        && event.target == target
+                        && (event.property == property || event.property == x11rb::NONE) =>
+                {
+                    break Ok(Some(event.property));
+                }
+                event => skipped.push_back(event),
+            }
+        };
+        // Handled by the next wait, in the order they arrived.
+        self.queue.borrow_mut().append(&mut skipped);
+        let value = match replied {
+            Ok(Some(x11rb::NONE)) => Ok(None),
+            Ok(Some(_)) => match self.read_property(property, deadline) {
+                // The owner may still send the rest of it.
+                Ok(None) => {
+                    self.properties.borrow_mut().abandoned.push_back(property);
+                    return Ok(None);
+                }
+                value => value,
+            },
+            Ok(None) => {
+                self.properties.borrow_mut().abandoned.push_back(property);
+                return Ok(None);
             }
+            Err(e) => Err(e),
+        };
+        self.properties.borrow_mut().free.push(property);
+        value
+    }
+
+    /// A property no conversion is waiting on.
+    fn take_property(&self) -> Result<Atom> {
+        let mut properties = self.properties.borrow_mut();
+        if let Some(property) = properties.free.pop() {
+            return Ok(property);
+        }
+        let context = &self.getter;
+        if properties.abandoned.len() >= MAX_ABANDONED {
+            // Its reply is not coming anymore.
+            let property = properties.abandoned.pop_front().unwrap();
+            context
+                .connection
+                .delete_property(context.window, property)?
+                .check()?;
+            return Ok(property);
+        }
+        let name = format!("CLIPD_PROPERTY_{}", properties.interned);
+        let property = context
+            .connection
+            .intern_atom(false, name.as_bytes())?
+            .reply()?
+            .atom;
+        properties.interned += 1;
+        Ok(property)
+    }
+
+    /// Reads and drops the late reply to a conversion that timed out, its property can be used
+    /// again after.
+    fn drain(&self, event: SelectionNotifyEvent) -> Result<()> {
+        let mut properties = self.properties.borrow_mut();
+        let index = match properties
+            .abandoned
+            .iter()
+            .position(|p| *p == event.property)
+        {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        properties.abandoned.remove(index);
+        drop(properties);
+        log::debug!("Drop late reply of {} to {}", event.selection, event.target);
+        self.read_property(event.property, None)?;
+        self.properties.borrow_mut().free.push(event.property);
+        Ok(())
+    }
 
-            return Ok(text);
+    /// Names of `atoms`, asked for in one round trip and cached.
+    fn atom_names(&self, atoms: &[Atom]) -> Result<Vec<String>> {
+        let connection = &self.getter.connection;
+        let mut cache = self.atom_names.borrow_mut();
+        let cookies = atoms
+            .iter()
+            .filter(|a| !cache.contains_key(a))
+            .map(|a| Ok((*a, connection.get_atom_name(*a)?)))
+            .collect::<Result<Vec<_>>>()?;
+        for (atom, cookie) in cookies {
+            let name = String::from_utf8_lossy(&cookie.reply()?.name).into_owned();
+            cache.insert(atom, name);
         }
+        Ok(atoms.iter().map(|a| cache[a].clone()).collect())
     }
 
-    fn read_utf8_string(&self, sequence_number: u64) -> Result<Option<String>> {
-        let context = &self.clipboard.getter;
-        let atoms = &context.atoms;
+    /// Whether the first mouse button is held, i.e. text may still be being selected.
+    pub fn is_pointer_pressed(&self) -> Result<bool> {
+        let context = &self.getter;
         let connection = &context.connection;
+        let root = connection.setup().roots[context.screen].root;
+        let reply = connection.query_pointer(root)?.reply()?;
+        Ok(reply.mask.contains(KeyButMask::BUTTON1))
+    }
 
+    /// Like `wait_for_event`, but returns `None` once woken or `deadline` passed.
+    fn wait_for_event(&self, deadline: Option<Instant>) -> Result<Option<Event>> {
+        let connection = &self.getter.connection;
         loop {
-            let (event, seq) = connection.wait_for_event_with_sequence()?;
-            log::trace!("event({:?}): {:?}", seq, event);
-            if seq < sequence_number {
-                continue;
+            if let Some(event) = self.queue.borrow_mut().pop_front() {
+                return Ok(Some(event));
             }
-            match event {
-                Event::XfixesSelectionNotify(event) => {
-                    connection
-                        .convert_selection(
-                            context.window,
-                            atoms.clipboard,
-                            atoms.utf8_string,
-                            atoms.property,
-                            event.timestamp,
-                        )?
-                        .check()?;
-                }
-                Event::SelectionNotify(event) => {
-                    if event.selection != atoms.clipboard {
-                        continue;
-                    };
+            if let Some(event) = connection.poll_for_event()? {
+                self.received(&event);
+                return Ok(Some(event));
+            }
+            connection.flush()?;
 
-                    if event.property == Atom::from(AtomEnum::NONE) {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
                         return Ok(None);
                     }
+                    // Rounded up, so that it does not spin for the last millisecond.
+                    timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+                }
+                None => -1,
+            };
+
+            let mut fds = [
+                libc::pollfd {
+                    fd: connection.stream().as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: self.wake_rx.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as _, timeout) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e.into());
+            }
+            if fds[1].revents != 0 {
+                self.drain_wake();
+                return Ok(None);
+            }
+        }
+    }
 
+    /// The app of `window`, `None` if it can not be told, e.g. because it is gone already.
+    fn app_of(&self, window: Window) -> Option<App> {
+        match self.apps.app_of(&self.getter.connection, window) {
+            Ok(app) if app != App::default() => Some(app),
+            Ok(_) => None,
+            Err(e) => {
+                log::debug!("Can not resolve the app of {}: {:?}", window, e);
+                None
+            }
+        }
+    }
+
+    /// Waits for a new owner of a selection `filter` wants, texts we stored ourselves are
+    /// skipped.
+    ///
+    /// The owner is checked and the text read later by [`X11Clipboard::read_changed`], so that
+    /// a burst of changes is looked at once. Returns `None` if woken by [`Waker::wake`] or once
+    /// `deadline` passed.
+    pub fn wait_change<F>(&self, filter: &F, deadline: Option<Instant>) -> Result<Option<Selection>>
+    where
+        F: ChangeFilter + ?Sized,
+    {
+        loop {
+            let event = match self.wait_for_event(deadline)? {
+                Some(e) => e,
+                None => return Ok(None),
+            };
+            log::trace!("event: {:?}", event);
+            let event = match event {
+                Event::XfixesSelectionNotify(e)
+                    if e.selection == self.manager_atoms.clipboard_manager =>
+                {
+                    self.manager_changed(&e)?;
+                    continue;
+                }
+                Event::XfixesSelectionNotify(e) => e,
+                // Replies to conversions that timed out.
+                Event::SelectionNotify(e) => {
+                    self.drain(e)?;
+                    continue;
+                }
+                Event::SelectionRequest(e)
+                    if e.selection == self.manager_atoms.clipboard_manager =>
+                {
+                    self.manage(e, filter)?;
+                    continue;
+                }
+                Event::SelectionClear(e) if e.selection == self.manager_atoms.clipboard_manager => {
+                    log::info!("Another program took over as clipboard manager");
+                    self.manager_owner.set(Manager::Other);
+                    continue;
+                }
+                _ => continue,
+            };
+            if event.subtype != xfixes::SelectionEvent::SET_SELECTION_OWNER {
+                self.forget_change(event.selection);
+                self.restore(event.selection)?;
+                continue;
+            }
+            // A new copy replaces the one kept for after its owner exited.
+            self.saved.borrow_mut().remove(&event.selection);
+            if event.owner == self.owner.window() {
+                self.forget_change(event.selection);
+                continue;
+            }
+            let selection = match self.selection(event.selection) {
+                Some(s) if filter.wants(s) => s,
+                _ => {
+                    self.forget_change(event.selection);
+                    continue;
+                }
+            };
+            let change = Change {
+                owner: event.owner,
+                owned_at: event.selection_timestamp,
+                time: event.timestamp,
+            };
+            self.changed
+                .borrow_mut()
+                .insert(event.selection, Some(change));
+            return Ok(Some(selection));
+        }
+    }
+
+    /// Keeps a change waiting to be read from being read, the selection has moved on.
+    fn forget_change(&self, selection: Atom) {
+        if let Some(changed) = self.changed.borrow_mut().get_mut(&selection) {
+            *changed = None;
+        }
+    }
+
+    /// Reads and deletes `property` of our window, following `INCR` transfers.
+    /// `None` if the owner stopped sending, or was not done at `deadline`.
+    fn read_property(
+        &self,
+        property: Atom,
+        deadline: Option<Instant>,
+    ) -> Result<Option<PropertyValue>> {
+        let context = &self.getter;
+        let connection = &context.connection;
+        let reply = connection
+            .get_property(true, context.window, property, AtomEnum::ANY, 0, u32::MAX)?
+            .reply()?;
+        if reply.type_ != context.atoms.incr {
+            return Ok(Some(PropertyValue {
+                type_: reply.type_,
+                format: reply.format,
+                data: reply.value,
+            }));
+        }
+
+        // Deleting the property above asked for the first chunk.
+        let size = reply.value32().and_then(|mut v| v.next()).unwrap_or(0);
+        log::debug!("INCR transfer, at least {} bytes", size);
+        let mut value = PropertyValue {
+            type_: x11rb::NONE,
+            format: 8,
+            data: Vec::with_capacity(size as usize),
+        };
+        let mut skipped = VecDeque::new();
+        let result = loop {
+            let event = match self.wait_for_event(Some(wait_deadline(deadline))) {
+                Ok(Some(e)) => e,
+                Ok(None) => {
+                    log::warn!("INCR transfer stalled after {} bytes", value.data.len());
+                    break Ok(None);
+                }
+                Err(e) => break Err(e),
+            };
+            match event {
+                Event::PropertyNotify(event)
+                    if event.window == context.window
+                        && event.atom == property
+                        && event.state == Property::NEW_VALUE =>
+                {
                     let reply = connection
-                        .get_property(
-                            false,
-                            context.window,
-                            atoms.property,
-                            AtomEnum::NONE,
-                            0,
-                            u32::MAX,
-                        )?
+                        .get_property(true, context.window, property, AtomEnum::ANY, 0, u32::MAX)?
                         .reply()?;
+                    if reply.type_ == x11rb::NONE {
+                        // Already deleted, not a chunk.
+                        continue;
+                    }
+                    if reply.value.is_empty() {
+                        break Ok(Some(value));
+                    }
+                    value.type_ = reply.type_;
+                    value.format = reply.format;
+                    value.data.extend_from_slice(&reply.value);
+                }
+                event => skipped.push_back(event),
+            }
+        };
+        self.queue.borrow_mut().append(&mut skipped);
+        result
+    }
+
+    /// Gives up the selections still held by us.
+    pub fn release(&self) -> Result<()> {
+        for selection in Selection::ALL {
+            log::debug!("Release {}", selection.name());
+            self.owner.release(self.atom(selection))?;
+        }
+        Ok(())
+    }
+
+    /// Owns `selection` with `value`, offered in every text target we read.
+    pub fn store_utf8_string(&self, selection: Selection, value: String) -> Result<()> {
+        let contents = self.text_atoms.contents(value, self.string_encoding.get());
+        self.owner.store(self.atom(selection), contents)
+    }
+
+    /// Owns `selection` with `value` in place of the plain text of the owner it was last read
+    /// from. The other targets of that owner, like `text/html` or `image/png`, are copied and
+    /// served unchanged. `false` if `selection` changed since the read, it is left as is then.
+    pub fn replace_text(&self, selection: Selection, value: String) -> Result<bool> {
+        let contents = self.text_atoms.contents(value, self.string_encoding.get());
+        let contents = self.copy_offer(self.atom(selection), contents, None)?;
+        self.store_unchanged(selection, contents)
+    }
+
+    /// Like [`X11Clipboard::replace_text`], but the text is only formatted when pasted:
+    /// `format` gets the app that pastes and returns the text it receives. `original` stays
+    /// available as `CLIPD_ORIGINAL`.
+    pub fn defer_text<F>(&self, selection: Selection, original: String, format: F) -> Result<bool>
+    where
+        F: Fn(&App) -> String + Send + Sync + 'static,
+    {
+        let (text_atoms, string_encoding) = (self.text_atoms, self.string_encoding.get());
+        let render: Render = Arc::new(move |app| text_atoms.contents(format(app), string_encoding));
+        let contents = Contents::default()
+            .with_lazy(text_atoms.all(), render, self.waker.renders.clone())
+            .with_type(
+                text_atoms.original,
+                text_atoms.utf8_string,
+                original.into_bytes(),
+            );
+        let contents = self.copy_offer(self.atom(selection), contents, None)?;
+        self.store_unchanged(selection, contents)
+    }
+
+    /// Owns `selection` with `contents` unless it changed since it was read, which copying the
+    /// other targets of its owner leaves plenty of time for. Returns whether it was stored.
+    fn store_unchanged(&self, selection: Selection, contents: Contents) -> Result<bool> {
+        if self.is_unchanged(selection)? == Some(false) {
+            log::debug!("{} changed while its targets were copied", selection.name());
+            return Ok(false);
+        }
+        self.owner.store(self.atom(selection), contents)?;
+        Ok(true)
+    }
+
+    /// Keeps `text` and the other targets the owner of `selection` offered when it was last
+    /// read, they are served once that owner is gone. Targets that do not fit in
+    /// [`SAVE_TIMEOUT`] and [`MAX_SAVED_SIZE`] are dropped.
+    pub fn save(&self, selection: Selection, text: String) -> Result<()> {
+        let atom = self.atom(selection);
+        let contents = self.text_atoms.contents(text, self.string_encoding.get());
+        let contents = self.copy_offer(atom, contents, Some(Budget::save()))?;
+        self.saved.borrow_mut().insert(atom, contents);
+        Ok(())
+    }
+
+    /// Serves what was saved of `selection`, its owner is gone.
+    fn restore(&self, selection: Atom) -> Result<()> {
+        let contents = self.saved.borrow_mut().remove(&selection);
+        if let Some(contents) = contents {
+            log::info!("Keep selection {} after its owner exited", selection);
+            self.owner.store(selection, contents)?;
+        }
+        Ok(())
+    }
 
-                    let text = if reply.type_ == atoms.utf8_string {
-                        Some(String::from_utf8(reply.value)?)
-                    } else {
-                        let name_reply = connection.get_atom_name(reply.type_)?.reply()?;
-                        log::trace!(
-                            "Ignore unexpected type: {:?}",
-                            String::from_utf8(name_reply.name)
-                        );
-                        None
-                    };
-                    return Ok(text);
+    /// Acts as the clipboard manager if no one else does, so apps hand `CLIPBOARD` over to us
+    /// when they exit. If another one does, we take over once it is gone.
+    pub fn set_clipboard_manager(&self, enabled: bool) -> Result<()> {
+        self.manager.set(enabled);
+        let context = &self.getter;
+        let connection = &context.connection;
+        let manager = self.manager_atoms.clipboard_manager;
+        match (enabled, self.manager_owner.get()) {
+            (true, Manager::Unknown) => {
+                let owner = connection.get_selection_owner(manager)?.reply()?.owner;
+                if owner != x11rb::NONE && owner != context.window {
+                    log::info!("Clipboard manager is already run by {}", owner);
+                    self.manager_owner.set(Manager::Other);
+                    return Ok(());
                 }
-                _ => (),
+                connection
+                    .set_selection_owner(context.window, manager, x11rb::CURRENT_TIME)?
+                    .check()?;
+                let owner = connection.get_selection_owner(manager)?.reply()?.owner;
+                if owner == context.window {
+                    log::info!("Acting as clipboard manager");
+                    self.manager_owner.set(Manager::Us);
+                } else {
+                    self.manager_owner.set(Manager::Other);
+                }
+            }
+            (false, Manager::Us) => {
+                connection
+                    .set_selection_owner(x11rb::NONE, manager, x11rb::CURRENT_TIME)?
+                    .check()?;
+                self.manager_owner.set(Manager::Unknown);
             }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Follows who runs the clipboard manager, and takes over once it is gone if we want to.
+    fn manager_changed(&self, event: &xfixes::SelectionNotifyEvent) -> Result<()> {
+        let owner = if event.subtype != xfixes::SelectionEvent::SET_SELECTION_OWNER
+            || event.owner == x11rb::NONE
+        {
+            Manager::Unknown
+        } else if event.owner == self.getter.window {
+            Manager::Us
+        } else {
+            Manager::Other
+        };
+        self.manager_owner.set(owner);
+        match owner {
+            Manager::Unknown => self.set_clipboard_manager(self.manager.get()),
+            _ => Ok(()),
         }
     }
 
-    pub fn store_utf8_string(&self, value: String) -> Result<()> {
-        let atoms = &self.clipboard.getter.atoms;
-        self.clipboard
-            .store(atoms.clipboard, atoms.utf8_string, value)?;
+    /// Answers a request to `CLIPBOARD_MANAGER`. `SAVE_TARGETS` asks us to take over
+    /// `CLIPBOARD` before its owner exits.
+    fn manage<F>(&self, event: SelectionRequestEvent, filter: &F) -> Result<()>
+    where
+        F: ChangeFilter + ?Sized,
+    {
+        let atoms = &self.getter.atoms;
+        let connection = &self.getter.connection;
+        let manager = self.manager_atoms;
+        // Obsolete clients leave the property empty.
+        let property = match event.property {
+            x11rb::NONE => event.target,
+            p => p,
+        };
+        let done = if event.target == atoms.targets {
+            connection.change_property32(
+                PropMode::REPLACE,
+                event.requestor,
+                property,
+                AtomEnum::ATOM,
+                &[atoms.targets, manager.save_targets],
+            )?;
+            true
+        } else if event.target == manager.save_targets {
+            // Not paused here, the copy would be lost for good.
+            let app = self.app_of(event.requestor);
+            filter.accepts(Selection::Clipboard, app.as_ref())
+                && match self.save_targets(&event, property) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::warn!("Save clipboard of {:?} failed: {:?}", app, e);
+                        false
+                    }
+                }
+        } else {
+            false
+        };
+        connection.send_event(
+            false,
+            event.requestor,
+            EventMask::NO_EVENT,
+            SelectionNotifyEvent {
+                response_type: SELECTION_NOTIFY_EVENT,
+                sequence: 0,
+                time: event.time,
+                requestor: event.requestor,
+                selection: event.selection,
+                target: event.target,
+                property: if done { property } else { x11rb::NONE },
+            },
+        )?;
+        connection.flush()?;
         Ok(())
     }
+
+    /// Copies the targets listed in `property` of the requestor, all if none, and owns
+    /// `CLIPBOARD` with them. Targets that do not fit in [`SAVE_TIMEOUT`] and [`MAX_SAVED_SIZE`]
+    /// are dropped.
+    fn save_targets(&self, event: &SelectionRequestEvent, property: Atom) -> Result<()> {
+        let connection = &self.getter.connection;
+        let clipboard = self.getter.atoms.clipboard;
+        let listed = match event.property {
+            x11rb::NONE => vec![],
+            _ => connection
+                .get_property(
+                    false,
+                    event.requestor,
+                    property,
+                    AtomEnum::ATOM,
+                    0,
+                    u32::MAX,
+                )?
+                .reply()?
+                .value32()
+                .map(|v| v.collect())
+                .unwrap_or_default(),
+        };
+        let budget = Budget::save();
+        let targets = match listed.is_empty() {
+            true => self.targets(clipboard, event.time, Some(budget.deadline))?,
+            false => listed,
+        };
+        let names = self.atom_names(&targets)?;
+        let targets = targets
+            .into_iter()
+            .zip(names)
+            .filter(|(_, name)| !META_TARGETS.contains(&name.as_str()))
+            .map(|(target, _)| target)
+            .collect();
+        let contents = Contents::default();
+        let contents = self.copy_targets(clipboard, targets, event.time, contents, Some(budget))?;
+        log::info!("Clipboard handed over by {}", event.requestor);
+        self.owner.store(clipboard, contents)?;
+        connection.change_property8(
+            PropMode::REPLACE,
+            event.requestor,
+            property,
+            self.manager_atoms.null,
+            &[],
+        )?;
+        Ok(())
+    }
+
+    /// Adds the other targets the last owner of `selection` offered to `contents`, as far as
+    /// `budget` goes.
+    fn copy_offer(
+        &self,
+        selection: Atom,
+        contents: Contents,
+        budget: Option<Budget>,
+    ) -> Result<Contents> {
+        let offer = match self.offers.borrow_mut().remove(&selection) {
+            Some(o) => o,
+            None => return Ok(contents),
+        };
+        self.copy_targets(selection, offer.targets, offer.time, contents, budget)
+    }
+
+    /// Adds `targets` of `selection` as its owner had them at `time` to `contents`, as far as
+    /// `budget` goes.
+    fn copy_targets(
+        &self,
+        selection: Atom,
+        targets: Vec<Atom>,
+        time: Timestamp,
+        mut contents: Contents,
+        mut budget: Option<Budget>,
+    ) -> Result<Contents> {
+        for target in targets {
+            let deadline = budget.map(|b| b.deadline);
+            if deadline.is_some_and(|d| d <= Instant::now()) {
+                log::debug!("Out of time, drop the other targets of {}", selection);
+                break;
+            }
+            let value = match self.convert_until(selection, target, time, deadline)? {
+                Some(v) => v,
+                None => {
+                    log::debug!("Drop target {} of {}", target, selection);
+                    continue;
+                }
+            };
+            if let Some(budget) = &mut budget {
+                if value.data.len() > budget.size {
+                    log::debug!("Drop target {} of {}, too large", target, selection);
+                    continue;
+                }
+                budget.size -= value.data.len();
+            }
+            contents = contents.with_value(target, value);
+        }
+        Ok(contents)
+    }
+}
+
+/// When to stop waiting for the next reply or chunk: after [`READ_TIMEOUT`], or at `deadline`
+/// if that comes first.
+fn wait_deadline(deadline: Option<Instant>) -> Instant {
+    let timeout = Instant::now() + READ_TIMEOUT;
+    deadline.map_or(timeout, |d| d.min(timeout))
+}
+
+/// Whether `e` means the connection to the X server is gone, rather than a request failed.
+pub fn is_disconnected(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.is::<ConnectionError>()
+            || matches!(cause.downcast_ref(), Some(ReplyError::ConnectionError(_)))
+            || matches!(
+                cause.downcast_ref(),
+                Some(ReplyOrIdError::ConnectionError(_))
+            )
+            || matches!(cause.downcast_ref(), Some(Error::XcbConnection(_)))
+    })
+}
+
+/// Non-blocking, close-on-exec `(read, write)` pair.
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    use x11rb::{
+        connection::Connection,
+        protocol::{
+            xproto::{
+                AtomEnum, ConnectionExt, EventMask, PropMode, SelectionNotifyEvent, WindowClass,
+                SELECTION_NOTIFY_EVENT,
+            },
+            Event,
+        },
+        wrapper::ConnectionExt as _,
+    };
+
+    use super::{is_disconnected, Contents, Display, X11Clipboard};
+    use crate::{
+        config::{App, Selection},
+        engine::ChangeFilter,
+    };
+
+    /// Reads every change.
+    struct All;
+
+    impl ChangeFilter for All {
+        fn wants(&self, _selection: Selection) -> bool {
+            true
+        }
+
+        fn accepts(&self, _selection: Selection, _owner: Option<&App>) -> bool {
+            true
+        }
+    }
+
+    /// A private X server, run the tests with `cargo test -- --ignored` where `Xvfb` is
+    /// installed.
+    struct Xvfb {
+        child: Child,
+        display: String,
+    }
+
+    impl Xvfb {
+        fn start() -> Self {
+            let mut child = Command::new("Xvfb")
+                .args(["-displayfd", "1", "-nolisten", "tcp"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("run Xvfb");
+            let mut line = String::new();
+            BufReader::new(child.stdout.take().unwrap())
+                .read_line(&mut line)
+                .unwrap();
+            Self {
+                child,
+                display: format!(":{}", line.trim()),
+            }
+        }
+
+        fn clipboard(&self) -> X11Clipboard {
+            X11Clipboard::connect(Some(&self.display)).unwrap()
+        }
+    }
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    /// Several times the largest property Xvfb accepts in one request.
+    fn large_text() -> String {
+        (0..200_000)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>()
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn incr_read_now() {
+        let xvfb = Xvfb::start();
+        let (writer, reader) = (xvfb.clipboard(), xvfb.clipboard());
+        let text = large_text();
+        assert!(text.len() > 4 * 262_140);
+
+        writer
+            .store_utf8_string(Selection::Clipboard, text.clone())
+            .unwrap();
+        let read = reader.read_utf8_string_now(Selection::Clipboard).unwrap();
+        assert_eq!(read.as_deref().map(str::len), Some(text.len()));
+        assert!(read.unwrap() == text);
+
+        writer
+            .store_utf8_string(Selection::Primary, "small".into())
+            .unwrap();
+        assert_eq!(
+            reader.read_utf8_string_now(Selection::Primary).unwrap(),
+            Some("small".into())
+        );
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn incr_wait() {
+        let xvfb = Xvfb::start();
+        let (writer, reader) = (xvfb.clipboard(), xvfb.clipboard());
+        let text = large_text();
+
+        writer
+            .store_utf8_string(Selection::Clipboard, text.clone())
+            .unwrap();
+        let deadline = Some(Instant::now() + Duration::from_secs(10));
+        let (selection, read) = reader
+            .wait_utf8_string(All, deadline)
+            .unwrap()
+            .expect("selection change");
+        assert_eq!(selection, Selection::Clipboard);
+        assert!(read == text);
+
+        // Our own stores are not reported.
+        reader
+            .store_utf8_string(Selection::Clipboard, "mine".into())
+            .unwrap();
+        let deadline = Some(Instant::now() + Duration::from_millis(200));
+        assert!(reader.wait_utf8_string(All, deadline).unwrap().is_none());
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn reconnect() {
+        let xvfb = Xvfb::start();
+        let clipboard = xvfb.clipboard();
+        let waker = clipboard.waker();
+        drop(xvfb);
+        let deadline = Some(Instant::now() + Duration::from_secs(10));
+        let e = clipboard.wait_utf8_string(All, deadline).unwrap_err();
+        assert!(is_disconnected(&e), "{:?}", e);
+
+        let xvfb = Xvfb::start();
+        let clipboard = clipboard
+            .reconnect(&Display::named(Some(&xvfb.display)))
+            .unwrap();
+        let writer = xvfb.clipboard();
+        writer
+            .store_utf8_string(Selection::Clipboard, "again".into())
+            .unwrap();
+        let (_, read) = clipboard
+            .wait_utf8_string(All, deadline)
+            .unwrap()
+            .expect("selection change");
+        assert_eq!(read, "again");
+
+        // The waker handed out before still wakes the new connection.
+        waker.wake();
+        assert!(clipboard.wait_utf8_string(All, None).unwrap().is_none());
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn is_unchanged() {
+        let xvfb = Xvfb::start();
+        let (writer, reader) = (xvfb.clipboard(), xvfb.clipboard());
+        assert_eq!(reader.is_unchanged(Selection::Clipboard).unwrap(), None);
+
+        writer
+            .store_utf8_string(Selection::Clipboard, "a".into())
+            .unwrap();
+        let deadline = Some(Instant::now() + Duration::from_secs(10));
+        reader
+            .wait_utf8_string(All, deadline)
+            .unwrap()
+            .expect("selection change");
+        assert_eq!(
+            reader.is_unchanged(Selection::Clipboard).unwrap(),
+            Some(true)
+        );
+
+        // Copied again by the same owner.
+        writer
+            .store_utf8_string(Selection::Clipboard, "b".into())
+            .unwrap();
+        assert_eq!(
+            reader.is_unchanged(Selection::Clipboard).unwrap(),
+            Some(false)
+        );
+        let (_, read) = reader
+            .wait_utf8_string(All, deadline)
+            .unwrap()
+            .expect("selection change");
+        assert_eq!(read, "b");
+        assert_eq!(
+            reader.is_unchanged(Selection::Clipboard).unwrap(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn late_reply() {
+        let xvfb = Xvfb::start();
+        let reader = xvfb.clipboard();
+        let clipboard = reader.atom(Selection::Clipboard);
+        let utf8_string = reader.getter.atoms.utf8_string;
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (late_tx, late_rx) = mpsc::channel::<()>();
+        let display = xvfb.display.clone();
+        // Answers the first request only after the reader gave up on it.
+        let owner = std::thread::spawn(move || {
+            let (connection, screen) = x11rb::connect(Some(&display)).unwrap();
+            let window = connection.generate_id().unwrap();
+            let root = connection.setup().roots[screen].root;
+            connection
+                .create_window(
+                    0,
+                    window,
+                    root,
+                    0,
+                    0,
+                    1,
+                    1,
+                    0,
+                    WindowClass::INPUT_ONLY,
+                    0,
+                    &Default::default(),
+                )
+                .unwrap()
+                .check()
+                .unwrap();
+            connection
+                .set_selection_owner(window, clipboard, x11rb::CURRENT_TIME)
+                .unwrap()
+                .check()
+                .unwrap();
+            ready_tx.send(()).unwrap();
+            for data in [&b"old"[..], b"new"] {
+                let request = loop {
+                    if let Event::SelectionRequest(e) = connection.wait_for_event().unwrap() {
+                        break e;
+                    }
+                };
+                if data == b"old" {
+                    late_rx.recv().unwrap();
+                }
+                connection
+                    .change_property8(
+                        PropMode::REPLACE,
+                        request.requestor,
+                        request.property,
+                        utf8_string,
+                        data,
+                    )
+                    .unwrap();
+                connection
+                    .send_event(
+                        false,
+                        request.requestor,
+                        EventMask::NO_EVENT,
+                        SelectionNotifyEvent {
+                            response_type: SELECTION_NOTIFY_EVENT,
+                            sequence: 0,
+                            time: request.time,
+                            requestor: request.requestor,
+                            selection: request.selection,
+                            target: request.target,
+                            property: request.property,
+                        },
+                    )
+                    .unwrap();
+                connection.flush().unwrap();
+            }
+        });
+        ready_rx.recv().unwrap();
+
+        assert_eq!(
+            reader
+                .convert(clipboard, utf8_string, x11rb::CURRENT_TIME)
+                .unwrap(),
+            None
+        );
+        late_tx.send(()).unwrap();
+        let value = reader
+            .convert(clipboard, utf8_string, x11rb::CURRENT_TIME)
+            .unwrap()
+            .expect("reply");
+        assert_eq!(value.data, b"new");
+        owner.join().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn replace_text_keeps_rich_targets() {
+        let xvfb = Xvfb::start();
+        let (writer, reader) = (xvfb.clipboard(), xvfb.clipboard());
+        let connection = &writer.getter.connection;
+        let html = connection
+            .intern_atom(false, b"text/html")
+            .unwrap()
+            .reply()
+            .unwrap()
+            .atom;
+        let clipboard = writer.atom(Selection::Clipboard);
+        let contents = Contents::default()
+            .with(writer.getter.atoms.utf8_string, b" a ".to_vec())
+            .with(html, b"<b>a</b>".to_vec());
+        writer.owner.store(clipboard, contents).unwrap();
+
+        let deadline = Some(Instant::now() + Duration::from_secs(10));
+        let (selection, text) = reader
+            .wait_utf8_string(All, deadline)
+            .unwrap()
+            .expect("selection change");
+        assert_eq!(text, " a ");
+        assert!(reader.replace_text(selection, "a".into()).unwrap());
+
+        let value = writer
+            .convert(clipboard, html, x11rb::CURRENT_TIME)
+            .unwrap()
+            .expect("text/html");
+        assert_eq!(value.data, b"<b>a</b>");
+        assert_eq!(
+            writer.read_utf8_string_now(Selection::Clipboard).unwrap(),
+            Some("a".into())
+        );
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn defer_text() {
+        let xvfb = Xvfb::start();
+        let (writer, reader) = (xvfb.clipboard(), xvfb.clipboard());
+        writer
+            .store_utf8_string(Selection::Clipboard, " a ".into())
+            .unwrap();
+        let deadline = Some(Instant::now() + Duration::from_secs(10));
+        let (selection, text) = reader
+            .wait_utf8_string(All, deadline)
+            .unwrap()
+            .expect("selection change");
+        // The requestor is found by its `WM_CLASS`.
+        writer
+            .getter
+            .connection
+            .change_property8(
+                PropMode::REPLACE,
+                writer.getter.window,
+                AtomEnum::WM_CLASS,
+                AtomEnum::STRING,
+                b"clipd-test\0Clipd\0",
+            )
+            .unwrap()
+            .check()
+            .unwrap();
+        let deferred = reader
+            .defer_text(selection, text.clone(), move |app| {
+                assert_eq!(app.class, ["clipd-test", "Clipd"]);
+                text.trim().to_uppercase()
+            })
+            .unwrap();
+        assert!(deferred);
+
+        assert_eq!(
+            writer.read_utf8_string_now(Selection::Clipboard).unwrap(),
+            Some("A".into())
+        );
+        let original = writer
+            .convert(
+                writer.atom(Selection::Clipboard),
+                writer.text_atoms.original,
+                x11rb::CURRENT_TIME,
+            )
+            .unwrap()
+            .expect("CLIPD_ORIGINAL");
+        assert_eq!(original.data, b" a ");
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn clipboard_manager() {
+        let xvfb = Xvfb::start();
+        let manager = xvfb.clipboard();
+        manager.set_clipboard_manager(true).unwrap();
+
+        let display = xvfb.display.clone();
+        let app = std::thread::spawn(move || {
+            let app = X11Clipboard::connect(Some(&display)).unwrap();
+            app.store_utf8_string(Selection::Clipboard, "kept".into())
+                .unwrap();
+            let atoms = app.manager_atoms;
+            app.convert(
+                atoms.clipboard_manager,
+                atoms.save_targets,
+                x11rb::CURRENT_TIME,
+            )
+            .unwrap()
+            .is_some()
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !app.is_finished() && Instant::now() < deadline {
+            let tick = Some(Instant::now() + Duration::from_millis(50));
+            manager.wait_utf8_string(All, tick).unwrap();
+        }
+        assert!(app.join().unwrap());
+
+        let connection = &manager.getter.connection;
+        let clipboard = manager.atom(Selection::Clipboard);
+        let owner = connection
+            .get_selection_owner(clipboard)
+            .unwrap()
+            .reply()
+            .unwrap()
+            .owner;
+        assert_eq!(owner, manager.owner.window());
+        assert_eq!(
+            xvfb.clipboard()
+                .read_utf8_string_now(Selection::Clipboard)
+                .unwrap(),
+            Some("kept".into())
+        );
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb"]
+    fn clipboard_manager_takes_over() {
+        let xvfb = Xvfb::start();
+        let other = xvfb.clipboard();
+        other.set_clipboard_manager(true).unwrap();
+        let clipboard = xvfb.clipboard();
+        clipboard.set_clipboard_manager(true).unwrap();
+        let manager = clipboard.manager_atoms.clipboard_manager;
+        let owner = |clipboard: &X11Clipboard| {
+            let connection = &clipboard.getter.connection;
+            connection
+                .get_selection_owner(manager)
+                .unwrap()
+                .reply()
+                .unwrap()
+                .owner
+        };
+        assert_eq!(owner(&clipboard), other.getter.window);
+
+        drop(other);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while owner(&clipboard) != clipboard.getter.window && Instant::now() < deadline {
+            let tick = Some(Instant::now() + Duration::from_millis(50));
+            clipboard.wait_utf8_string(All, tick).unwrap();
+        }
+        assert_eq!(owner(&clipboard), clipboard.getter.window);
+    }
 }
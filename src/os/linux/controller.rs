@@ -0,0 +1,528 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    ipc::{self, Request, Response, State, Status},
+    os::SystemServiceController,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceState {
+    Stopped,
+    StartPending,
+    StopPending,
+    Running,
+}
+
+#[derive(Debug)]
+struct UnitStatus {
+    loaded: bool,
+    state: ServiceState,
+}
+
+/// `systemctl --user`, the binary is configurable so that tests can use a fake one.
+#[derive(Debug)]
+pub struct Systemctl {
+    program: PathBuf,
+}
+
+impl Systemctl {
+    pub fn new(program: PathBuf) -> Self {
+        Self { program }
+    }
+
+    fn run(&self, args: &[&str]) -> Result<String> {
+        log::debug!("{:?} --user {:?}", self.program, args);
+        let output = Command::new(&self.program)
+            .arg("--user")
+            .args(args)
+            .output()
+            .with_context(|| format!("run {:?}", self.program))?;
+        if !output.status.success() {
+            bail!(
+                "systemctl --user {} failed with {}: {}",
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The environment of the user manager, which session scripts update with the current
+    /// `DISPLAY`.
+    pub fn environment(&self) -> Result<HashMap<String, String>> {
+        Ok(self
+            .run(&["show-environment"])?
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect())
+    }
+}
+
+impl Default for Systemctl {
+    fn default() -> Self {
+        Self::new(PathBuf::from("systemctl"))
+    }
+}
+
+pub struct SystemdServiceController {
+    service_name: String,
+    description: String,
+    executable_path: PathBuf,
+    unit_dir: PathBuf,
+    systemctl: Systemctl,
+    /// Control socket of a running instance, whether started by systemd or not.
+    socket_path: PathBuf,
+}
+
+impl SystemdServiceController {
+    pub fn new(
+        service_name: String,
+        description: String,
+        executable_path: PathBuf,
+        unit_dir: PathBuf,
+        systemctl: Systemctl,
+        socket_path: PathBuf,
+    ) -> Self {
+        Self {
+            service_name,
+            description,
+            executable_path,
+            unit_dir,
+            systemctl,
+            socket_path,
+        }
+    }
+
+    fn request(&self, request: Request) -> Result<Response> {
+        ipc::request(&self.socket_path, request)?.ok()
+    }
+
+    fn wait_exit(&self) -> Result<()> {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(2);
+        while start.elapsed() < timeout {
+            if ipc::request(&self.socket_path, Request::Status).is_err() {
+                println!("{:?}", ServiceState::Stopped);
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        bail!("wait {:?} exit timeout", self.service_name)
+    }
+
+    fn unit_name(&self) -> String {
+        format!("{}.service", self.service_name)
+    }
+
+    fn unit_path(&self) -> PathBuf {
+        self.unit_dir.join(self.unit_name())
+    }
+
+    fn unit(&self, arguments: Vec<OsString>) -> String {
+        let exec_start = std::iter::once(self.executable_path.clone().into_os_string())
+            // systemd supervises the process itself, it has to stay in the foreground.
+            .chain(arguments.into_iter().filter(|a| a != "--daemon"))
+            .map(|a| quote(&a.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "[Unit]\n\
+             Description={}\n\
+             PartOf=graphical-session.target\n\
+             After=graphical-session.target\n\
+             \n\
+             [Service]\n\
+             ExecStart={}\n\
+             ExecReload=/bin/kill -HUP $MAINPID\n\
+             Restart=on-failure\n\
+             \n\
+             [Install]\n\
+             WantedBy=graphical-session.target\n",
+            self.description, exec_start
+        )
+    }
+
+    fn query_status(&self) -> Result<UnitStatus> {
+        let output = self.systemctl.run(&[
+            "show",
+            &self.unit_name(),
+            "--property=LoadState,ActiveState",
+        ])?;
+        let properties: HashMap<&str, &str> = output
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let state = match properties.get("ActiveState").copied() {
+            Some("active") | Some("reloading") => ServiceState::Running,
+            Some("activating") => ServiceState::StartPending,
+            Some("deactivating") => ServiceState::StopPending,
+            _ => ServiceState::Stopped,
+        };
+        Ok(UnitStatus {
+            loaded: properties.get("LoadState").copied() == Some("loaded"),
+            state,
+        })
+    }
+
+    fn query_installed(&self) -> Result<UnitStatus> {
+        let status = self.query_status()?;
+        if !status.loaded && !self.unit_path().exists() {
+            bail!("{} not installed", self.service_name)
+        }
+        Ok(status)
+    }
+
+    fn wait(&self, state: ServiceState) -> Result<()> {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(2);
+        while start.elapsed() < timeout {
+            if self.query_status()?.state == state {
+                println!("{:?}", state);
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        bail!("wait {:?} {:?} timeout", self.service_name, state)
+    }
+}
+
+impl SystemServiceController for SystemdServiceController {
+    fn install(&self, arguments: Vec<OsString>) -> Result<()> {
+        let service_name = &self.service_name;
+        let unit_path = self.unit_path();
+        if unit_path.exists() {
+            bail!("{} has installed", service_name)
+        }
+
+        std::fs::create_dir_all(&self.unit_dir)
+            .with_context(|| format!("create {:?}", self.unit_dir))?;
+        std::fs::write(&unit_path, self.unit(arguments))
+            .with_context(|| format!("write {:?}", unit_path))?;
+        self.systemctl.run(&["daemon-reload"])?;
+        self.systemctl.run(&["enable", &self.unit_name()])?;
+        println!("{} installed", service_name);
+
+        Ok(())
+    }
+
+    fn start(&self, _arguments: Vec<OsString>) -> Result<()> {
+        let service_name = &self.service_name;
+        let state = self.query_installed()?.state;
+        if state != ServiceState::Stopped {
+            bail!("{} is {:?}", service_name, state)
+        }
+
+        println!("Start {}", service_name);
+        self.systemctl.run(&["start", &self.unit_name()])?;
+        self.wait(ServiceState::Running)
+    }
+
+    fn pause(&self) -> Result<()> {
+        println!("Pause {}", self.service_name);
+        self.request(Request::Pause)?;
+        println!("{:?}", State::Paused);
+        Ok(())
+    }
+
+    fn resume(&self) -> Result<()> {
+        println!("Resume {}", self.service_name);
+        self.request(Request::Resume)?;
+        println!("{:?}", State::Running);
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        let service_name = &self.service_name;
+        // Let systemd stop its own unit, otherwise it would restart it.
+        if let Ok(status) = self.query_status() {
+            if status.loaded && status.state != ServiceState::Stopped {
+                println!("Stop {}", service_name);
+                self.systemctl.run(&["stop", &self.unit_name()])?;
+                return self.wait(ServiceState::Stopped);
+            }
+        }
+        if self.request(Request::Stop).is_err() {
+            bail!("{} has stoped", service_name)
+        }
+
+        println!("Stop {}", service_name);
+        self.wait_exit()
+    }
+
+    fn status(&self) -> Result<Status> {
+        if let Ok(Response::Status(status)) = self.request(Request::Status) {
+            return Ok(status);
+        }
+        let state = match self.query_installed()?.state {
+            ServiceState::Stopped => State::Stopped,
+            ServiceState::StartPending => State::StartPending,
+            ServiceState::StopPending => State::StopPending,
+            ServiceState::Running => State::Running,
+        };
+        Ok(Status::new(state))
+    }
+
+    fn reload(&self) -> Result<()> {
+        println!("Reload {}", self.service_name);
+        self.request(Request::Reload)?;
+        Ok(())
+    }
+
+    fn restart(&self, _arguments: Vec<OsString>) -> Result<()> {
+        let service_name = &self.service_name;
+        self.query_installed()?;
+
+        println!("Restart {}", service_name);
+        self.systemctl.run(&["restart", &self.unit_name()])?;
+        self.wait(ServiceState::Running)
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        let service_name = &self.service_name;
+        let state = self.query_installed()?.state;
+        if state == ServiceState::Running {
+            bail!("{} is {:?}", service_name, state)
+        }
+
+        self.systemctl.run(&["disable", &self.unit_name()])?;
+        let unit_path = self.unit_path();
+        if unit_path.exists() {
+            std::fs::remove_file(&unit_path).with_context(|| format!("remove {:?}", unit_path))?;
+        }
+        self.systemctl.run(&["daemon-reload"])?;
+        println!("{} is deleted", service_name);
+
+        Ok(())
+    }
+}
+
+/// Quotes an `ExecStart=` word, see systemd.service(5) "Command lines".
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use super::{ServiceState, Systemctl, SystemdServiceController};
+    use crate::{
+        ipc::{ControlServer, Request, Response, State, Status},
+        os::SystemServiceController,
+    };
+
+    struct FakeSystemctl {
+        dir: PathBuf,
+    }
+
+    impl FakeSystemctl {
+        /// Keeps the unit state in a file, `start`/`restart` activate it and `stop` deactivates.
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("clipd-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let script = format!(
+                r#"#!/bin/sh
+dir='{dir}'
+echo "$@" >> "$dir/calls"
+[ "$1" = "--user" ] || exit 2
+case "$2" in
+    start|restart) echo active > "$dir/state" ;;
+    stop) echo inactive > "$dir/state" ;;
+    show)
+        if [ -f "$dir/units/$3" ]; then echo LoadState=loaded; else echo LoadState=not-found; fi
+        echo "ActiveState=$(cat "$dir/state" 2>/dev/null || echo inactive)"
+        ;;
+    show-environment) cat "$dir/environment" ;;
+    fail) echo "boom" >&2; exit 1 ;;
+esac
+"#,
+                dir = dir.display()
+            );
+            let program = dir.join("systemctl");
+            std::fs::write(&program, script).unwrap();
+            std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+            Self { dir }
+        }
+
+        fn controller(&self) -> SystemdServiceController {
+            SystemdServiceController::new(
+                "clipd".into(),
+                "clipd test".into(),
+                PathBuf::from("/opt/clip d/clipd"),
+                self.dir.join("units"),
+                Systemctl::new(self.dir.join("systemctl")),
+                self.dir.join("clipd.sock"),
+            )
+        }
+
+        fn calls(&self) -> Vec<String> {
+            std::fs::read_to_string(self.dir.join("calls"))
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    impl Drop for FakeSystemctl {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        let fake = FakeSystemctl::new("systemctl-lifecycle");
+        let controller = fake.controller();
+
+        assert!(controller.status().is_err());
+        assert!(controller.start(vec![]).is_err());
+
+        controller
+            .install(
+                ["run", "--daemon", "--config", "/home/me/clip d.toml"]
+                    .map(OsString::from)
+                    .to_vec(),
+            )
+            .unwrap();
+        let unit = std::fs::read_to_string(fake.dir.join("units/clipd.service")).unwrap();
+        assert!(unit.contains("Description=clipd test\n"));
+        assert!(unit.contains(
+            "ExecStart=\"/opt/clip d/clipd\" \"run\" \"--config\" \"/home/me/clip d.toml\"\n"
+        ));
+        assert!(controller.install(vec![]).is_err());
+
+        assert_eq!(
+            controller.query_status().unwrap().state,
+            ServiceState::Stopped
+        );
+        assert!(controller.stop().is_err());
+        controller.start(vec![]).unwrap();
+        assert_eq!(
+            controller.query_status().unwrap().state,
+            ServiceState::Running
+        );
+        assert_eq!(controller.status().unwrap(), Status::new(State::Running));
+        assert!(controller.start(vec![]).is_err());
+        assert!(controller.uninstall().is_err());
+        controller.restart(vec![]).unwrap();
+        controller.stop().unwrap();
+        controller.uninstall().unwrap();
+        assert!(!fake.dir.join("units/clipd.service").exists());
+        assert!(controller.status().is_err());
+
+        assert_eq!(
+            fake.calls()
+                .into_iter()
+                .filter(|c| !c.starts_with("--user show"))
+                .collect::<Vec<_>>(),
+            [
+                "--user daemon-reload",
+                "--user enable clipd.service",
+                "--user start clipd.service",
+                "--user restart clipd.service",
+                "--user stop clipd.service",
+                "--user disable clipd.service",
+                "--user daemon-reload",
+            ]
+        );
+    }
+
+    #[test]
+    fn control_socket() {
+        let fake = FakeSystemctl::new("systemctl-socket");
+        let controller = fake.controller();
+        assert!(controller.pause().is_err());
+        assert!(controller.reload().is_err());
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let server = Arc::new(Mutex::new(None));
+        let handler = {
+            let paused = paused.clone();
+            let server = server.clone();
+            move |request| match request {
+                Request::Pause | Request::Resume => {
+                    paused.store(request == Request::Pause, Ordering::SeqCst);
+                    Response::Ok
+                }
+                Request::Status => Response::Status(Status {
+                    pid: Some(1),
+                    ..Status::new(match paused.load(Ordering::SeqCst) {
+                        true => State::Paused,
+                        false => State::Running,
+                    })
+                }),
+                Request::Stop => {
+                    // Removes the socket like an exiting service.
+                    server.lock().unwrap().take();
+                    Response::Ok
+                }
+                Request::Reload => Response::error("broken config"),
+            }
+        };
+        *server.lock().unwrap() =
+            Some(ControlServer::listen(fake.dir.join("clipd.sock"), handler).unwrap());
+
+        controller.pause().unwrap();
+        assert!(paused.load(Ordering::SeqCst));
+        let status = controller.status().unwrap();
+        assert_eq!(status.state, State::Paused);
+        assert_eq!(status.pid, Some(1));
+        controller.resume().unwrap();
+        assert!(!paused.load(Ordering::SeqCst));
+        assert!(controller.reload().is_err());
+        controller.stop().unwrap();
+        assert!(controller.stop().is_err());
+        assert!(fake.calls().iter().all(|c| c.starts_with("--user show")));
+    }
+
+    #[test]
+    fn systemctl_failure() {
+        let fake = FakeSystemctl::new("systemctl-failure");
+        let err = Systemctl::new(fake.dir.join("systemctl"))
+            .run(&["fail"])
+            .unwrap_err();
+        assert!(err.to_string().ends_with(": boom"), "{}", err);
+    }
+
+    #[test]
+    fn environment() {
+        let fake = FakeSystemctl::new("systemctl-environment");
+        let systemctl = Systemctl::new(fake.dir.join("systemctl"));
+        assert!(systemctl.environment().is_err());
+
+        std::fs::write(
+            fake.dir.join("environment"),
+            "HOME=/home/me\nDISPLAY=:1\nXAUTHORITY=/run/user/1000/xauth_a=b\n",
+        )
+        .unwrap();
+        let environment = systemctl.environment().unwrap();
+        assert_eq!(environment["DISPLAY"], ":1");
+        assert_eq!(environment["XAUTHORITY"], "/run/user/1000/xauth_a=b");
+        assert_eq!(fake.calls().last().unwrap(), "--user show-environment");
+    }
+}
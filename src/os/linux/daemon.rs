@@ -0,0 +1,123 @@
+use std::{
+    io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{util::dirs, SERVICE_NAME};
+
+/// Set on the detached child so that it does not detach again.
+const DETACHED_ENV: &str = "CLIPD_DETACHED";
+
+pub fn is_detached() -> bool {
+    std::env::var_os(DETACHED_ENV).is_some()
+}
+
+pub fn pid_path() -> PathBuf {
+    dirs::runtime_dir().join(format!("{}.pid", SERVICE_NAME))
+}
+
+/// Starts this executable again with the same arguments in a new session, with stdio
+/// redirected to `/dev/null`. A fresh process is used instead of `fork`, the logger thread
+/// is already running and would not survive it.
+pub fn spawn_detached() -> Result<u32> {
+    let exe = std::env::current_exe()?;
+    let mut command = Command::new(exe);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DETACHED_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().context("spawn daemon")?;
+
+    // Catch the daemon failing at startup, e.g. no X display.
+    std::thread::sleep(Duration::from_millis(200));
+    if let Some(status) = child.try_wait()? {
+        bail!("{} daemon exited: {}", SERVICE_NAME, status)
+    }
+    Ok(child.id())
+}
+
+/// Holds `<pid>` in a file while alive.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> Result<Self> {
+        Self::check(&path)?;
+        std::fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("write {:?}", path))?;
+        log::debug!("Pid file created: {:?}", path);
+        Ok(Self { path })
+    }
+
+    /// Fails if the file names a process that is still running.
+    pub fn check(path: &Path) -> Result<()> {
+        if let Some(pid) = Self::read(path) {
+            if pid != std::process::id() && is_alive(pid) {
+                bail!("{} is running, pid: {}", SERVICE_NAME, pid)
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(path: &Path) -> Option<u32> {
+        std::fs::read_to_string(path).ok()?.trim().parse().ok()
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if Self::read(&self.path) == Some(std::process::id()) {
+            log::debug!("Remove pid file: {:?}", self.path);
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn is_alive(pid: u32) -> bool {
+    let ret = unsafe { libc::kill(pid as _, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PidFile;
+
+    #[test]
+    fn pid_file() {
+        let path = std::env::temp_dir().join(format!("clipd-pid-{}.pid", std::process::id()));
+
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        assert_eq!(PidFile::read(&path), Some(std::process::id()));
+        PidFile::check(&path).unwrap();
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // pid 1 is always alive
+        std::fs::write(&path, "1\n").unwrap();
+        assert!(PidFile::check(&path).is_err());
+        assert!(PidFile::create(path.clone()).is_err());
+
+        // stale
+        std::fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
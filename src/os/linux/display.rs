@@ -0,0 +1,209 @@
+use std::{io, path::PathBuf};
+
+use anyhow::Result;
+use x11_clipboard::{Atoms, Context, RustConnection};
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{AtomEnum, ConnectionExt, CreateWindowAux, EventMask, WindowClass},
+    reexports::x11rb_protocol::{
+        parse_display::parse_display,
+        xauth::{self, Family},
+    },
+    rust_connection::{ConnectError, DefaultStream},
+    COPY_DEPTH_FROM_PARENT,
+};
+
+const MIT_MAGIC_COOKIE_1: &[u8] = b"MIT-MAGIC-COOKIE-1";
+
+/// An X display, and the authority file to get in with. They are passed around rather than set
+/// in the environment, which other threads read at any time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Display {
+    /// `$DISPLAY` if `None`.
+    pub name: Option<String>,
+    /// `$XAUTHORITY`, or else `~/.Xauthority`, if `None`.
+    pub xauthority: Option<PathBuf>,
+}
+
+impl Display {
+    pub fn named(name: Option<&str>) -> Self {
+        Self {
+            name: name.map(str::to_owned),
+            xauthority: None,
+        }
+    }
+
+    /// Opens a connection with a window and the atoms of [`Context::new`].
+    pub fn context(&self) -> Result<Context> {
+        let (connection, screen) = self.connect()?;
+        let window = connection.generate_id()?;
+        let root = match connection.setup().roots.get(screen) {
+            Some(root) => root,
+            None => return Err(ConnectError::InvalidScreen.into()),
+        };
+        connection
+            .create_window(
+                COPY_DEPTH_FROM_PARENT,
+                window,
+                root.root,
+                0,
+                0,
+                1,
+                1,
+                0,
+                WindowClass::INPUT_OUTPUT,
+                root.root_visual,
+                &CreateWindowAux::new()
+                    .event_mask(EventMask::STRUCTURE_NOTIFY | EventMask::PROPERTY_CHANGE),
+            )?
+            .check()?;
+
+        let cookies = [
+            connection.intern_atom(false, b"CLIPBOARD")?,
+            connection.intern_atom(false, b"THIS_CLIPBOARD_OUT")?,
+            connection.intern_atom(false, b"TARGETS")?,
+            connection.intern_atom(false, b"UTF8_STRING")?,
+            connection.intern_atom(false, b"INCR")?,
+        ];
+        let [clipboard, property, targets, utf8_string, incr] =
+            cookies.map(|c| c.reply().map(|r| r.atom));
+        let atoms = Atoms {
+            primary: AtomEnum::PRIMARY.into(),
+            clipboard: clipboard?,
+            property: property?,
+            targets: targets?,
+            string: AtomEnum::STRING.into(),
+            utf8_string: utf8_string?,
+            incr: incr?,
+        };
+        Ok(Context {
+            connection,
+            screen,
+            window,
+            atoms,
+        })
+    }
+
+    /// Like [`RustConnection::connect`], but with the cookie from our authority file.
+    fn connect(&self) -> Result<(RustConnection, usize)> {
+        let parsed = match parse_display(self.name.as_deref()) {
+            Some(p) => p,
+            None => return Err(ConnectError::DisplayParsingError.into()),
+        };
+        let screen = parsed.screen.into();
+        let mut error = None;
+        for address in parsed.connect_instruction() {
+            let stream = match DefaultStream::connect(&address) {
+                Ok(s) => s,
+                Err(e) => {
+                    log::debug!("Connect to {:?} failed: {:?}", address, e);
+                    error = Some(e);
+                    continue;
+                }
+            };
+            let (family, address) = stream.peer_addr()?;
+            let (name, data) = match self.auth(family, &address, parsed.display) {
+                Ok(auth) => auth.unwrap_or_default(),
+                Err(e) => {
+                    log::debug!("Connect without a cookie, reading it failed: {:?}", e);
+                    Default::default()
+                }
+            };
+            let connection =
+                RustConnection::connect_to_stream_with_auth_info(stream, screen, name, data)?;
+            return Ok((connection, screen));
+        }
+        Err(match error {
+            Some(e) => ConnectError::IoError(e),
+            None => ConnectError::DisplayParsingError,
+        }
+        .into())
+    }
+
+    fn auth(&self, family: Family, address: &[u8], display: u16) -> io::Result<Option<Auth>> {
+        match &self.xauthority {
+            Some(path) => find_auth(&std::fs::read(path)?, family, address, display),
+            None => xauth::get_auth(family, address, display),
+        }
+    }
+}
+
+/// The name and data of an authorization protocol.
+type Auth = (Vec<u8>, Vec<u8>);
+
+/// Finds the cookie for `display` at `address` in the contents of an authority file, the way
+/// Xlib does.
+fn find_auth(
+    mut entries: &[u8],
+    family: Family,
+    address: &[u8],
+    display: u16,
+) -> io::Result<Option<Auth>> {
+    let display = display.to_string();
+    while !entries.is_empty() {
+        let entry_family = take(&mut entries, 2)?;
+        let entry_family = Family::from(u16::from_be_bytes([entry_family[0], entry_family[1]]));
+        let entry_address = field(&mut entries)?;
+        let number = field(&mut entries)?;
+        let name = field(&mut entries)?;
+        let data = field(&mut entries)?;
+
+        let address_matches = family == Family::WILD
+            || entry_family == Family::WILD
+            || (family == entry_family && address == entry_address);
+        let number_matches = number.is_empty() || number == display.as_bytes();
+        if address_matches && number_matches && name == MIT_MAGIC_COOKIE_1 {
+            return Ok(Some((name.to_vec(), data.to_vec())));
+        }
+    }
+    Ok(None)
+}
+
+/// Takes a field prefixed with its big-endian length from the front of `entries`.
+fn field<'a>(entries: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = take(entries, 2)?;
+    take(entries, u16::from_be_bytes([len[0], len[1]]).into())
+}
+
+fn take<'a>(entries: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if entries.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (head, tail) = entries.split_at(len);
+    *entries = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use x11rb::reexports::x11rb_protocol::xauth::Family;
+
+    use super::{find_auth, MIT_MAGIC_COOKIE_1};
+
+    /// `xauth -f file add ZweiLED/unix:1 . deadbeef` and `xauth -f file add 1.2.3.4:2 . aabbccdd`
+    const ENTRIES: &[u8] = &[
+        0x01, 0x00, 0x00, 0x07, b'Z', b'w', b'e', b'i', b'L', b'E', b'D', 0x00, 0x01, b'1', 0x00,
+        0x12, b'M', b'I', b'T', b'-', b'M', b'A', b'G', b'I', b'C', b'-', b'C', b'O', b'O', b'K',
+        b'I', b'E', b'-', b'1', 0x00, 0x04, 0xde, 0xad, 0xbe, 0xef, 0x00, 0x00, 0x00, 0x04, 1, 2,
+        3, 4, 0x00, 0x01, b'2', 0x00, 0x12, b'M', b'I', b'T', b'-', b'M', b'A', b'G', b'I', b'C',
+        b'-', b'C', b'O', b'O', b'K', b'I', b'E', b'-', b'1', 0x00, 0x04, 0xaa, 0xbb, 0xcc, 0xdd,
+    ];
+
+    #[test]
+    fn auth() {
+        let cookie = |data: [u8; 4]| Some((MIT_MAGIC_COOKIE_1.to_vec(), data.to_vec()));
+        let find =
+            |family, address: &[u8], display| find_auth(ENTRIES, family, address, display).unwrap();
+        assert_eq!(
+            find(Family::LOCAL, b"ZweiLED", 1),
+            cookie([0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(
+            find(Family::INTERNET, &[1, 2, 3, 4], 2),
+            cookie([0xaa, 0xbb, 0xcc, 0xdd])
+        );
+        assert_eq!(find(Family::LOCAL, b"ZweiLED", 2), None);
+        assert_eq!(find(Family::INTERNET, &[1, 2, 3, 5], 2), None);
+        assert!(find_auth(&ENTRIES[..ENTRIES.len() - 1], Family::LOCAL, b"other", 0).is_err());
+    }
+}
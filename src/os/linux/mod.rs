@@ -1,33 +1,72 @@
-mod service;
+mod app;
+mod backend;
 mod clipboard;
+mod controller;
+mod daemon;
+mod display;
+mod mutex;
+mod owner;
+mod reconnect;
+mod reload;
+mod service;
+mod signal;
+mod sync;
+mod text;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use controller::{Systemctl, SystemdServiceController};
+use mutex::create_app_mutex;
 
 use super::OsAbstractionLayer;
-use crate::os_stub;
+use crate::{config::Config, util::dirs, SERVICE_NAME};
 
-#[derive(Debug)]
-pub struct LinuxOAL;
+const CLIPD_MUTEX_NAME: &str = "clipd.lock";
+
+#[derive(Debug, Default)]
+pub struct LinuxOAL {
+    config: Config,
+}
 
 impl OsAbstractionLayer for LinuxOAL {
-    fn init(&mut self, _args: &crate::Args) -> Result<()> {
+    fn init(&mut self, _args: &crate::Args, config: Config) -> Result<()> {
+        self.config = config;
         Ok(())
     }
 
     fn run_clipd(&self, daemon: bool) -> Result<()> {
-        if daemon {
-            os_stub!()
+        // Two instances would keep rewriting each other's selections.
+        let mutex_path = dirs::runtime_dir().join(CLIPD_MUTEX_NAME);
+        if daemon && !daemon::is_detached() {
+            drop(create_app_mutex(&mutex_path)?);
+            let pid = daemon::spawn_detached()?;
+            log::info!("Daemon started, pid: {}", pid);
+            println!("{} started, pid: {}", SERVICE_NAME, pid);
+            return Ok(());
+        }
+
+        let mutex = create_app_mutex(&mutex_path)?;
+        log::debug!("AppMutex created: {:?}", mutex);
+        if !daemon {
+            return service::ClipdService::new(&self.config).run();
         }
-        service::ClipdService::default().run()
-    }
 
-    fn service_controller(&self) -> anyhow::Result<Box<dyn super::SystemServiceController>> {
-        os_stub!()
+        std::env::set_current_dir("/")?;
+        let _pid_file = daemon::PidFile::create(daemon::pid_path())?;
+        service::ClipdService::new(&self.config).run()
     }
-}
 
-impl Default for LinuxOAL {
-    fn default() -> Self {
-        Self
+    fn service_controller(&self) -> Result<Box<dyn super::SystemServiceController>> {
+        let unit_dir = dirs::config_home()
+            .context("Can not find the config directory, HOME is not set")?
+            .join("systemd")
+            .join("user");
+        Ok(Box::new(SystemdServiceController::new(
+            SERVICE_NAME.into(),
+            SERVICE_NAME.into(),
+            std::env::current_exe()?,
+            unit_dir,
+            Systemctl::default(),
+            crate::ipc::socket_path(),
+        )))
     }
 }
@@ -0,0 +1,61 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::SERVICE_NAME;
+
+/// An exclusive `flock` on a file, released when dropped or when the process exits.
+#[derive(Debug)]
+pub struct AppMutex {
+    _file: File,
+}
+
+pub fn create_app_mutex(path: &Path) -> Result<AppMutex> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("open {:?}", path))?;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(e).with_context(|| format!("flock {:?}", path));
+        }
+        let mut pid = String::new();
+        file.read_to_string(&mut pid).ok();
+        bail!("{} is already running, pid: {}", SERVICE_NAME, pid.trim())
+    }
+
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    Ok(AppMutex { _file: file })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::create_app_mutex;
+
+    #[test]
+    fn single_instance() {
+        let path = std::env::temp_dir().join(format!("clipd-mutex-{}.lock", std::process::id()));
+        let mutex = create_app_mutex(&path).unwrap();
+        // flock is held per open file, a second open in the same process conflicts too.
+        let e = create_app_mutex(&path).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("clipd is already running, pid: {}", std::process::id())
+        );
+        drop(mutex);
+        drop(create_app_mutex(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{bail, Result};
+use x11_clipboard::{Atom, Context, Window};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{
+            AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, PropMode, Property,
+            SelectionNotifyEvent, SelectionRequestEvent, Timestamp, SELECTION_NOTIFY_EVENT,
+        },
+        Event,
+    },
+    wrapper::ConnectionExt as _,
+};
+
+use super::{app::AppResolver, display::Display};
+use crate::config::App;
+
+/// Size of a `ChangeProperty` request without its data.
+const CHANGE_PROPERTY_HEADER: usize = 24;
+
+/// Data served for a selection, by target.
+#[derive(Debug, Clone, Default)]
+pub struct Contents {
+    targets: Vec<Target>,
+    lazy: Option<Lazy>,
+}
+
+/// Builds the data of some targets for the app that asks for one of them.
+pub type Render = Arc<dyn Fn(&App) -> Contents + Send + Sync>;
+
+/// Drops what lazy contents rendered so far, e.g. once what they render with changed.
+#[derive(Debug, Clone, Default)]
+pub struct Renders {
+    epoch: Arc<AtomicU64>,
+}
+
+impl Renders {
+    pub fn invalidate(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone)]
+struct Lazy {
+    targets: Vec<Atom>,
+    render: Render,
+    renders: Renders,
+    /// Requestors resolved so far, kept as long as the contents are served.
+    apps: Arc<Mutex<HashMap<Window, App>>>,
+    /// What each requestor got for its latest paste.
+    rendered: Arc<Mutex<HashMap<Window, Rendered>>>,
+}
+
+/// A paste, told apart by the time of its requests, and the epoch of [`Renders`] it was
+/// rendered in.
+struct Rendered {
+    time: Timestamp,
+    epoch: u64,
+    contents: Contents,
+}
+
+impl std::fmt::Debug for Lazy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lazy")
+            .field("targets", &self.targets)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A property as read from or written to a window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PropertyValue {
+    /// `TEXT` for example is answered with the type of a concrete encoding.
+    pub type_: Atom,
+    /// 8, 16 or 32, `data` holds the items in native byte order.
+    pub format: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+struct Target {
+    target: Atom,
+    type_: Atom,
+    format: u8,
+    data: Arc<Vec<u8>>,
+}
+
+impl Contents {
+    pub fn with(self, target: Atom, data: Vec<u8>) -> Self {
+        self.with_type(target, target, data)
+    }
+
+    pub fn with_type(self, target: Atom, type_: Atom, data: Vec<u8>) -> Self {
+        self.with_value(
+            target,
+            PropertyValue {
+                type_,
+                format: 8,
+                data,
+            },
+        )
+    }
+
+    pub fn with_value(mut self, target: Atom, value: PropertyValue) -> Self {
+        self.targets.push(Target {
+            target,
+            type_: value.type_,
+            format: value.format,
+            data: Arc::new(value.data),
+        });
+        self
+    }
+
+    /// Serves `targets` with what `render` returns at the time they are requested, until
+    /// `renders` is invalidated.
+    pub fn with_lazy(mut self, targets: Vec<Atom>, render: Render, renders: Renders) -> Self {
+        self.lazy = Some(Lazy {
+            targets,
+            render,
+            renders,
+            apps: Default::default(),
+            rendered: Default::default(),
+        });
+        self
+    }
+
+    fn targets(&self) -> impl Iterator<Item = Atom> + '_ {
+        let lazy = self.lazy.iter().flat_map(|l| l.targets.iter().copied());
+        lazy.chain(self.targets.iter().map(|t| t.target))
+    }
+
+    /// The data of `target` for `requestor`, asked for at `time`. Lazy targets are rendered
+    /// once per paste, the requestor is only resolved by `resolve` once. Pastes at
+    /// `CurrentTime` can not be told apart and are rendered every time.
+    fn get<F>(&self, target: Atom, requestor: Window, time: Timestamp, resolve: F) -> Option<Target>
+    where
+        F: FnOnce(Window) -> App,
+    {
+        let lazy = match &self.lazy {
+            Some(lazy) if lazy.targets.contains(&target) => lazy,
+            _ => return self.target(target),
+        };
+        let epoch = lazy.renders.epoch();
+        let mut rendered = lazy.rendered.lock().unwrap();
+        if let Some(r) = rendered.get(&requestor) {
+            if r.time == time && r.epoch == epoch && time != x11rb::CURRENT_TIME {
+                return r.contents.target(target);
+            }
+        }
+        let app = lazy
+            .apps
+            .lock()
+            .unwrap()
+            .entry(requestor)
+            .or_insert_with(|| resolve(requestor))
+            .clone();
+        // Rendered contents are not lazy themselves.
+        let contents = (lazy.render)(&app);
+        let found = contents.target(target);
+        rendered.insert(
+            requestor,
+            Rendered {
+                time,
+                epoch,
+                contents,
+            },
+        );
+        found
+    }
+
+    fn target(&self, target: Atom) -> Option<Target> {
+        self.targets.iter().find(|t| t.target == target).cloned()
+    }
+}
+
+/// An `INCR` transfer to a requestor, see ICCCM 2.7.2.
+struct Transfer {
+    type_: Atom,
+    format: u8,
+    data: Arc<Vec<u8>>,
+    pos: usize,
+}
+
+/// Owns selections on its own connection and answers conversion requests from a thread.
+pub struct SelectionOwner {
+    context: Arc<Context>,
+    contents: Arc<Mutex<HashMap<Atom, Contents>>>,
+}
+
+impl SelectionOwner {
+    pub fn new(display: &Display) -> Result<Self> {
+        let context = Arc::new(display.context()?);
+        // Larger properties are sent with INCR, in chunks of this size. It is a multiple of 4, so
+        // chunks never split an item.
+        let chunk_size =
+            context.connection.setup().maximum_request_length as usize * 4 - CHANGE_PROPERTY_HEADER;
+        log::debug!("INCR chunk size: {}", chunk_size);
+        let contents = Arc::new(Mutex::new(HashMap::new()));
+
+        let server = Server {
+            apps: AppResolver::new(&context.connection)?,
+            context: context.clone(),
+            contents: contents.clone(),
+            chunk_size,
+            transfers: HashMap::new(),
+        };
+        std::thread::Builder::new()
+            .name("selection-owner".into())
+            .spawn(move || server.run())?;
+        Ok(Self { context, contents })
+    }
+
+    pub fn window(&self) -> Window {
+        self.context.window
+    }
+
+    pub fn store(&self, selection: Atom, contents: Contents) -> Result<()> {
+        let connection = &self.context.connection;
+        self.contents.lock().unwrap().insert(selection, contents);
+        connection
+            .set_selection_owner(self.context.window, selection, x11rb::CURRENT_TIME)?
+            .check()?;
+        if !self.is_owner(selection)? {
+            self.contents.lock().unwrap().remove(&selection);
+            bail!("Can not own selection {}", selection)
+        }
+        Ok(())
+    }
+
+    /// Gives up `selection` if it is still held by us.
+    pub fn release(&self, selection: Atom) -> Result<()> {
+        if self.is_owner(selection)? {
+            self.context
+                .connection
+                .set_selection_owner(x11rb::NONE, selection, x11rb::CURRENT_TIME)?
+                .check()?;
+        }
+        self.contents.lock().unwrap().remove(&selection);
+        Ok(())
+    }
+
+    fn is_owner(&self, selection: Atom) -> Result<bool> {
+        let connection = &self.context.connection;
+        let owner = connection.get_selection_owner(selection)?.reply()?.owner;
+        Ok(owner == self.context.window)
+    }
+}
+
+struct Server {
+    apps: AppResolver,
+    context: Arc<Context>,
+    contents: Arc<Mutex<HashMap<Atom, Contents>>>,
+    chunk_size: usize,
+    transfers: HashMap<(Window, Atom), Transfer>,
+}
+
+impl Server {
+    fn run(mut self) {
+        loop {
+            let event = match self.context.connection.wait_for_event() {
+                Ok(e) => e,
+                Err(e) => {
+                    log::error!("Selection owner connection closed: {:?}", e);
+                    return;
+                }
+            };
+            let result = match event {
+                Event::SelectionRequest(event) => self.request(event),
+                Event::PropertyNotify(event) if event.state == Property::DELETE => {
+                    self.next_chunk(event.window, event.atom)
+                }
+                Event::DestroyNotify(event) => {
+                    self.transfers.retain(|(w, _), _| *w != event.window);
+                    Ok(())
+                }
+                Event::SelectionClear(event) => {
+                    log::debug!("Selection {} cleared", event.selection);
+                    self.contents.lock().unwrap().remove(&event.selection);
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = result.and_then(|_| Ok(self.context.connection.flush()?)) {
+                log::warn!("Serve selection failed: {:?}", e);
+            }
+        }
+    }
+
+    fn request(&mut self, event: SelectionRequestEvent) -> Result<()> {
+        let atoms = &self.context.atoms;
+        let connection = &self.context.connection;
+        // Obsolete clients leave the property empty.
+        let property = match event.property {
+            x11rb::NONE => event.target,
+            p => p,
+        };
+        let contents = self.contents.lock().unwrap().get(&event.selection).cloned();
+        let converted = match contents {
+            None => false,
+            Some(contents) if event.target == atoms.targets => {
+                let mut targets = vec![atoms.targets];
+                targets.extend(contents.targets());
+                connection.change_property32(
+                    PropMode::REPLACE,
+                    event.requestor,
+                    property,
+                    AtomEnum::ATOM,
+                    &targets,
+                )?;
+                true
+            }
+            Some(contents) => {
+                let target = contents.get(event.target, event.requestor, event.time, |w| {
+                    self.app_of(w)
+                });
+                match target {
+                    None => false,
+                    Some(Target {
+                        type_,
+                        format,
+                        data,
+                        ..
+                    }) if data.len() <= self.chunk_size => {
+                        change_property(
+                            connection,
+                            event.requestor,
+                            property,
+                            type_,
+                            format,
+                            &data,
+                        )?;
+                        true
+                    }
+                    Some(Target {
+                        type_,
+                        format,
+                        data,
+                        ..
+                    }) => {
+                        log::debug!("INCR {} bytes to {}", data.len(), event.requestor);
+                        connection.change_window_attributes(
+                            event.requestor,
+                            &ChangeWindowAttributesAux::new().event_mask(
+                                EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY,
+                            ),
+                        )?;
+                        connection.change_property32(
+                            PropMode::REPLACE,
+                            event.requestor,
+                            property,
+                            atoms.incr,
+                            &[data.len().try_into().unwrap_or(u32::MAX)],
+                        )?;
+                        self.transfers.insert(
+                            (event.requestor, property),
+                            Transfer {
+                                type_,
+                                format,
+                                data,
+                                pos: 0,
+                            },
+                        );
+                        true
+                    }
+                }
+            }
+        };
+
+        connection.send_event(
+            false,
+            event.requestor,
+            EventMask::NO_EVENT,
+            SelectionNotifyEvent {
+                response_type: SELECTION_NOTIFY_EVENT,
+                sequence: 0,
+                time: event.time,
+                requestor: event.requestor,
+                selection: event.selection,
+                target: event.target,
+                property: if converted { property } else { x11rb::NONE },
+            },
+        )?;
+        Ok(())
+    }
+
+    fn app_of(&self, requestor: Window) -> App {
+        let app = self
+            .apps
+            .app_of(&self.context.connection, requestor)
+            .unwrap_or_else(|e| {
+                log::warn!("Can not resolve requestor {}: {:?}", requestor, e);
+                App::default()
+            });
+        log::debug!("Requested by {:?}", app);
+        app
+    }
+
+    /// The requestor deleted the property, it is ready for the next chunk.
+    fn next_chunk(&mut self, requestor: Window, property: Atom) -> Result<()> {
+        let connection = &self.context.connection;
+        let transfer = match self.transfers.get_mut(&(requestor, property)) {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let len = self.chunk_size.min(transfer.data.len() - transfer.pos);
+        change_property(
+            connection,
+            requestor,
+            property,
+            transfer.type_,
+            transfer.format,
+            &transfer.data[transfer.pos..][..len],
+        )?;
+        transfer.pos += len;
+        // The zero-length chunk ends the transfer.
+        if len == 0 {
+            self.transfers.remove(&(requestor, property));
+            if !self.transfers.keys().any(|(w, _)| *w == requestor) {
+                connection.change_window_attributes(
+                    requestor,
+                    &ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaces `property` with `data` of `format`, see [`PropertyValue`].
+fn change_property(
+    connection: &impl Connection,
+    window: Window,
+    property: Atom,
+    type_: Atom,
+    format: u8,
+    data: &[u8],
+) -> Result<()> {
+    let len = data.len() / (format as usize / 8).max(1);
+    connection.change_property(
+        PropMode::REPLACE,
+        window,
+        property,
+        type_,
+        format,
+        len as u32,
+        data,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::Cell,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use super::{Contents, Render, Renders};
+    use crate::config::App;
+
+    #[test]
+    fn lazy_renders_each_paste_once() {
+        let rendered = Arc::new(AtomicUsize::new(0));
+        let render: Render = {
+            let rendered = rendered.clone();
+            Arc::new(move |app| {
+                rendered.fetch_add(1, Ordering::SeqCst);
+                Contents::default()
+                    .with(1, app.class.concat().into())
+                    .with(3, b"c".to_vec())
+            })
+        };
+        let contents = Contents::default()
+            .with_lazy(vec![1, 3], render, Renders::default())
+            .with(2, b"b".to_vec());
+        let resolved = Cell::new(0);
+        let resolve = |window: u32| {
+            resolved.set(resolved.get() + 1);
+            App {
+                class: vec![format!("app{}", window)],
+                exe: None,
+            }
+        };
+
+        // Clones share what was rendered, like the contents of each request.
+        for contents in [contents.clone(), contents.clone()] {
+            let target = contents.get(1, 10, 100, resolve).unwrap();
+            assert_eq!(*target.data, b"app10");
+        }
+        assert_eq!(*contents.get(3, 10, 100, resolve).unwrap().data, b"c");
+        assert_eq!(rendered.load(Ordering::SeqCst), 1);
+        // The next paste is rendered again, for the same app.
+        assert_eq!(*contents.get(1, 10, 200, resolve).unwrap().data, b"app10");
+        assert_eq!(rendered.load(Ordering::SeqCst), 2);
+        assert_eq!(resolved.get(), 1);
+
+        assert_eq!(*contents.get(1, 11, 100, resolve).unwrap().data, b"app11");
+        assert_eq!(resolved.get(), 2);
+        assert_eq!(*contents.get(2, 12, 100, resolve).unwrap().data, b"b");
+        assert!(contents.get(4, 12, 100, resolve).is_none());
+        assert_eq!(resolved.get(), 2);
+        assert_eq!(rendered.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn lazy_renders_again_once_invalid() {
+        let text = Arc::new(Mutex::new("a"));
+        let render: Render = {
+            let text = text.clone();
+            Arc::new(move |_| Contents::default().with(1, text.lock().unwrap().as_bytes().to_vec()))
+        };
+        let renders = Renders::default();
+        let contents = Contents::default().with_lazy(vec![1], render, renders.clone());
+        let get = |time| contents.get(1, 10, time, |_| App::default()).unwrap().data;
+
+        // Pastes at CurrentTime, like xclip does, are never reused.
+        assert_eq!(*get(x11rb::CURRENT_TIME), b"a");
+        *text.lock().unwrap() = "b";
+        assert_eq!(*get(x11rb::CURRENT_TIME), b"b");
+
+        assert_eq!(*get(100), b"b");
+        *text.lock().unwrap() = "c";
+        assert_eq!(*get(100), b"b");
+        // E.g. reloaded or paused.
+        renders.invalidate();
+        assert_eq!(*get(100), b"c");
+    }
+}
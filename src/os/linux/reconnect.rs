@@ -0,0 +1,67 @@
+use std::{path::PathBuf, time::Duration};
+
+use super::{controller::Systemctl, display::Display};
+
+const FIRST_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Delays between attempts to reach the X server again, doubled after each attempt.
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Self { next: FIRST_DELAY }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (delay * 2).min(MAX_DELAY);
+        delay
+    }
+}
+
+/// The display to connect to now. A restarted session may be on another display, which login
+/// scripts publish in the systemd user environment, so `DISPLAY` and `XAUTHORITY` are taken
+/// from there if it has them.
+pub fn current_display(systemctl: &Systemctl) -> Display {
+    let mut environment = match systemctl.environment() {
+        Ok(e) => e,
+        Err(e) => {
+            log::debug!("Keep DISPLAY, no systemd user environment: {:?}", e);
+            Default::default()
+        }
+    };
+    for key in ["DISPLAY", "XAUTHORITY"] {
+        if let Some(value) = environment.get(key) {
+            if std::env::var(key).ok().as_ref() != Some(value) {
+                log::info!("{} changed to {}", key, value);
+            }
+        }
+    }
+    Display {
+        name: environment
+            .remove("DISPLAY")
+            .or_else(|| std::env::var("DISPLAY").ok()),
+        xauthority: environment.remove("XAUTHORITY").map(PathBuf::from),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new();
+        let delays = (0..9).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000, 30000].map(Duration::from_millis)
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    ffi::OsStr,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use encoding_rs::Encoding;
+use inotify::{Inotify, WatchMask};
+
+use crate::{
+    config::{AppMatch, Config, ConfigError, FormatOn, Selection, SourceConfig, SyncConfig},
+    engine::SharedFormatters,
+    fmt::StringFormatter,
+};
+
+/// See [`Config::requestor_formatters`].
+pub type SharedRequestors = Arc<RwLock<Vec<(AppMatch, Option<StringFormatter>)>>>;
+
+/// Rebuilds the formatters of a running service from its configuration file.
+#[derive(Clone)]
+pub struct Reloader {
+    config: Arc<Mutex<Config>>,
+    formatters: SharedFormatters,
+    requestors: SharedRequestors,
+}
+
+impl Reloader {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: Arc::new(Mutex::new(config.clone())),
+            formatters: Arc::new(RwLock::new(config.selection_formatters())),
+            requestors: Arc::new(RwLock::new(config.requestor_formatters())),
+        }
+    }
+
+    pub fn formatters(&self) -> SharedFormatters {
+        self.formatters.clone()
+    }
+
+    pub fn persist(&self, selection: Selection) -> bool {
+        self.config.lock().unwrap().selection.get(selection).persist
+    }
+
+    pub fn source(&self) -> SourceConfig {
+        self.config.lock().unwrap().source.clone()
+    }
+
+    pub fn requestors(&self) -> SharedRequestors {
+        self.requestors.clone()
+    }
+
+    pub fn sync(&self) -> SyncConfig {
+        self.config.lock().unwrap().sync
+    }
+
+    pub fn format_on(&self) -> FormatOn {
+        self.config.lock().unwrap().selection.format_on
+    }
+
+    pub fn string_encoding(&self) -> Option<&'static Encoding> {
+        self.config.lock().unwrap().selection.string_encoding
+    }
+
+    pub fn debounce(&self) -> Duration {
+        self.config.lock().unwrap().selection.debounce
+    }
+
+    pub fn config_path(&self) -> Option<PathBuf> {
+        self.config.lock().unwrap().path.clone()
+    }
+
+    /// Swaps in a formatter built from the file, a broken file keeps the current one.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let mut config = self.config.lock().unwrap();
+        let new_config = config.reload().map_err(|e| {
+            log::error!("Reload config failed, keep the current formatter: {}", e);
+            e
+        })?;
+        let formatters = new_config.selection_formatters();
+        for (selection, formatter) in &formatters {
+            log::info!(
+                "Config reloaded from {:?}, {} rules: {:?}",
+                new_config.path,
+                selection.name(),
+                formatter.rules().collect::<Vec<_>>()
+            );
+        }
+        *self.formatters.write().unwrap() = formatters;
+        *self.requestors.write().unwrap() = new_config.requestor_formatters();
+        *config = new_config;
+        Ok(())
+    }
+
+    /// Reloads whenever the configuration file is written, replaced or removed.
+    pub fn watch(&self) -> Result<()> {
+        let path = match self.config.lock().unwrap().path.clone() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        // Editors usually replace the file, so watch the directory instead of the file.
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir.to_owned(), name.to_owned()),
+            _ => return Ok(()),
+        };
+        let mut inotify = Inotify::init().context("inotify init")?;
+        inotify
+            .watches()
+            .add(
+                &dir,
+                WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::DELETE,
+            )
+            .with_context(|| format!("watch {:?}", dir))?;
+        log::debug!("Watching config {:?}", path);
+
+        let reloader = self.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            loop {
+                let events = match inotify.read_events_blocking(&mut buffer) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        log::error!("Read inotify events failed: {:?}", e);
+                        break;
+                    }
+                };
+                let changed = events
+                    .filter(|e| e.name == Some(OsStr::new(&name)))
+                    .inspect(|e| log::trace!("inotify: {:?}", e))
+                    .count();
+                if changed > 0 {
+                    let _ = reloader.reload();
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use super::Reloader;
+    use crate::config::{Config, Selection};
+
+    fn temp_config(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clipd-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("config.toml")
+    }
+
+    fn rules(reloader: &Reloader) -> Vec<String> {
+        let formatters = reloader.formatters();
+        let formatters = formatters.read().unwrap();
+        formatters[&Selection::Clipboard]
+            .rules()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn reload() {
+        let path = temp_config("reload");
+        std::fs::write(&path, "[format]\nrules = [\"trim-cr\"]").unwrap();
+        let reloader = Reloader::new(&Config::from_file(&path).unwrap());
+        assert_eq!(rules(&reloader), ["trim-cr"]);
+
+        std::fs::write(&path, "[format]\nrules = [\"trim-end-lf\"]").unwrap();
+        reloader.reload().unwrap();
+        assert_eq!(rules(&reloader), ["trim-end-lf"]);
+
+        std::fs::write(&path, "[format]\nrules = [\"nope\"]").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(rules(&reloader), ["trim-end-lf"]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        reloader.reload().unwrap();
+        assert_eq!(
+            rules(&reloader),
+            Config::default().formatter().rules().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn watch() {
+        let path = temp_config("watch");
+        std::fs::write(&path, "[format]\nrules = [\"trim-cr\"]").unwrap();
+        let reloader = Reloader::new(&Config::from_file(&path).unwrap());
+        reloader.watch().unwrap();
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, "[format]\nrules = [\"trim-end-lf\"]").unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+
+        let start = Instant::now();
+        while rules(&reloader) != ["trim-end-lf"] {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "config not reloaded"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,29 +1,595 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
 use anyhow::Result;
 
-use crate::fmt::{Formatter, StringFormatter};
+use crate::{
+    config::{App, Config, ConfigError, FormatOn, Selection},
+    engine::{ChangeFilter, ClipboardBackend, ClipdEngine, Formatted, Formatters, Pause, PingPong},
+    ipc::{self, ControlServer, DisplayStatus, Request, Response, State, Status},
+};
+
+use super::{
+    backend::X11Backend,
+    clipboard::{self, Waker, X11Clipboard},
+    controller::Systemctl,
+    reconnect::{self, Backoff},
+    reload::Reloader,
+    signal,
+    sync::SelectionSync,
+};
 
-use super::clipboard::X11Clipboard;
+pub struct ClipdService {
+    reloader: Reloader,
+    pause_on_start: bool,
+}
 
-#[derive(Default)]
-pub struct ClipdService;
+/// State shared by the clipboard loop, the control socket and the signal handler.
+#[derive(Clone)]
+pub struct Control {
+    reloader: Reloader,
+    /// Queries and changes the pause state of the engine.
+    pause: Pause,
+    stopping: Arc<AtomicBool>,
+    waker: Waker,
+    formatters: Formatters,
+    ping_pong: Arc<Mutex<PingPong>>,
+    display: Arc<Mutex<DisplayStatus>>,
+    started_at: Instant,
+}
 
 impl ClipdService {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            reloader: Reloader::new(config),
+            pause_on_start: config.pause_on_start,
+        }
+    }
+
     pub fn run(&self) -> Result<()> {
-        let formatter = StringFormatter::default();
         let clipboard = X11Clipboard::new()?;
-        loop {
-            let text = match clipboard.wait_utf8_string() {
-                Ok(t) => t,
-                Err(e) => {
+        let waker = clipboard.waker();
+        let filter = ConfigFilter::new(self.reloader.clone());
+        let mut engine = ClipdEngine::new(
+            X11Backend::new(clipboard, Box::new(filter.clone())),
+            filter.formatters,
+        );
+        engine.set_paused(self.pause_on_start);
+        let control = Control::new(self.reloader.clone(), &engine, waker);
+
+        if let Err(e) = self.reloader.watch() {
+            log::warn!("Config will not be reloaded on change: {:?}", e);
+        }
+        signal::listen(control.clone())?;
+        let _server = {
+            let control = control.clone();
+            ControlServer::listen(ipc::socket_path(), move |r| control.handle(r))?
+        };
+
+        let mut sync = SelectionSync::new(self.reloader.sync());
+        while !control.is_stopping() {
+            sync.set_config(self.reloader.sync());
+            engine.set_debounce(self.reloader.debounce());
+            let clipboard = engine.backend().clipboard();
+            clipboard.set_string_encoding(self.reloader.string_encoding());
+            if let Err(e) =
+                clipboard.set_clipboard_manager(self.reloader.persist(Selection::Clipboard))
+            {
+                log::warn!("Can not act as clipboard manager: {:?}", e);
+            }
+            let result = match engine.wait(sync.deadline()) {
+                Ok(Some((selection, text))) => {
+                    match self.handle(&mut engine, &control, &mut sync, selection, text) {
+                        Err(e) if !clipboard::is_disconnected(&e) => {
+                            log::error!("Handle {} change failed: {:?}", selection.name(), e);
+                            continue;
+                        }
+                        result => result,
+                    }
+                }
+                Ok(None) => {
+                    if let Err(e) = self.sync_settled(&mut engine, &control, &mut sync) {
+                        log::error!("Sync selection failed: {:?}", e);
+                    }
+                    continue;
+                }
+                Err(e) if !clipboard::is_disconnected(&e) => {
                     log::error!("Get clipboard text failed: {:?}", e);
                     continue;
                 }
+                Err(e) => Err(e),
             };
-            let fmt_result = formatter.fmt(&text)?;
-            log::trace!("{:?}", fmt_result);
-            if fmt_result.has_changed() {
-                clipboard.store_utf8_string(fmt_result.data)?;
+            if let Err(e) = result {
+                log::error!("Lost the connection to the X server: {:?}", e);
+                match self.reconnect(engine.backend().clipboard(), &control) {
+                    Some(c) => engine.backend_mut().set_clipboard(c),
+                    None => break,
+                }
+            }
+        }
+
+        log::info!("Stopping");
+        if let Err(e) = engine.backend().clipboard().release() {
+            log::warn!("Release selection failed: {:?}", e);
+        }
+        Ok(())
+    }
+
+    /// Formats `text` read from `selection` and writes it back or mirrors it.
+    fn handle<B: ClipboardBackend>(
+        &self,
+        engine: &mut ClipdEngine<B>,
+        control: &Control,
+        sync: &mut SelectionSync,
+        selection: Selection,
+        text: String,
+    ) -> Result<()> {
+        let text = match self.reloader.format_on() {
+            FormatOn::Paste if !engine.is_unchanged(selection)? => {
+                log::debug!("{} changed since the read", selection.name());
+                return Ok(());
+            }
+            FormatOn::Paste if control.formatters.contains(selection) => {
+                if !self.defer(engine, control, selection, &text)? {
+                    // The newer copy is handled next, a looping one is left to its owner.
+                    return Ok(());
+                }
+                text
+            }
+            _ => match engine.rewrite(selection, text)? {
+                Formatted::Changed(text) => text,
+                Formatted::Unchanged(text) => {
+                    self.save(engine, selection, &text);
+                    text
+                }
+                // The newer copy is handled next.
+                Formatted::Stale(_) => return Ok(()),
+                // Likely another clipboard manager, leave the copy to it.
+                Formatted::BackedOff(_) => return Ok(()),
+            },
+        };
+        if let Some(to) = sync.changed(selection, &text, Instant::now()) {
+            self.mirror(engine, control, sync, to, text)?;
+        }
+        Ok(())
+    }
+
+    /// Connects to the X server again, waiting longer after each failed attempt. Returns
+    /// `None` if stopped first.
+    fn reconnect(&self, clipboard: &X11Clipboard, control: &Control) -> Option<X11Clipboard> {
+        let systemctl = Systemctl::default();
+        let mut backoff = Backoff::new();
+        control.display.lock().unwrap().connected = false;
+        while !control.is_stopping() {
+            if clipboard.sleep(backoff.next_delay()) {
+                continue;
+            }
+            let target = reconnect::current_display(&systemctl);
+            let result = clipboard.reconnect(&target);
+            let mut display = control.display.lock().unwrap();
+            display.name = target.name;
+            match result {
+                Ok(clipboard) => {
+                    log::info!("Reconnected to {:?}", display.name);
+                    *display = DisplayStatus {
+                        name: display.name.take(),
+                        connected: true,
+                        attempts: 0,
+                        error: None,
+                    };
+                    return Some(clipboard);
+                }
+                Err(e) => {
+                    display.attempts += 1;
+                    log::warn!(
+                        "Connect to {:?} failed {} times: {:?}",
+                        display.name,
+                        display.attempts,
+                        e
+                    );
+                    display.error = Some(e.to_string());
+                }
+            }
+        }
+        None
+    }
+
+    /// Keeps a copy we did not take over, if `selection` persists.
+    fn save<B: ClipboardBackend>(
+        &self,
+        engine: &mut ClipdEngine<B>,
+        selection: Selection,
+        text: &str,
+    ) {
+        if !self.reloader.persist(selection) {
+            return;
+        }
+        if let Err(e) = engine.save(selection, text) {
+            log::warn!("Save {} failed: {:?}", selection.name(), e);
+        }
+    }
+
+    /// Owns `selection` and formats `text` whenever it is pasted, `false` if `selection` changed
+    /// since the read or rewrites are backing off.
+    fn defer<B: ClipboardBackend>(
+        &self,
+        engine: &mut ClipdEngine<B>,
+        control: &Control,
+        selection: Selection,
+        text: &str,
+    ) -> Result<bool> {
+        let (control, original) = (control.clone(), text.to_owned());
+        // Counted once per copy, by whichever profile renders its first paste.
+        let counted = AtomicBool::new(false);
+        engine.defer(selection, text, move |app| {
+            if control.is_paused() {
+                return original.clone();
+            }
+            let count = !counted.swap(true, Ordering::SeqCst);
+            match control.format_for(selection, app, original.clone(), count) {
+                Ok(t) => t,
+                Err(e) => {
+                    log::error!("Format on paste failed: {:?}", e);
+                    original.clone()
+                }
             }
+        })
+    }
+
+    /// Stores `text` in `to`, unless another program keeps putting something else there.
+    fn mirror<B: ClipboardBackend>(
+        &self,
+        engine: &mut ClipdEngine<B>,
+        control: &Control,
+        sync: &mut SelectionSync,
+        to: Selection,
+        text: String,
+    ) -> Result<()> {
+        let (formatted, matched) = control.formatters.format(to, text)?;
+        let text = formatted.into_text();
+        if sync.mirror(to, &text) && engine.mirror(to, text)? {
+            control.formatters.rewrote(matched);
+        }
+        Ok(())
+    }
+
+    /// Mirrors PRIMARY once the user stopped selecting.
+    fn sync_settled<B: ClipboardBackend>(
+        &self,
+        engine: &mut ClipdEngine<B>,
+        control: &Control,
+        sync: &mut SelectionSync,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let (from, to) = match sync.due(now) {
+            Some(d) if !control.is_paused() => d,
+            _ => return Ok(()),
+        };
+        if engine.backend().is_selecting()? {
+            log::trace!("Still selecting, postpone sync");
+            sync.postpone(from, now);
+            return Ok(());
+        }
+        // What was read when the owner changed may be a half-finished drag.
+        match engine.backend_mut().read_current(from)? {
+            Some(text) => self.mirror(engine, control, sync, to, text),
+            None => Ok(()),
         }
     }
 }
+
+/// Reads the changes that are formatted, mirrored or kept, from the apps the config accepts.
+/// Changes while paused are ignored by the engine.
+#[derive(Clone)]
+struct ConfigFilter {
+    reloader: Reloader,
+    formatters: Formatters,
+}
+
+impl ConfigFilter {
+    fn new(reloader: Reloader) -> Self {
+        Self {
+            formatters: Formatters::new(reloader.formatters()),
+            reloader,
+        }
+    }
+}
+
+impl ChangeFilter for ConfigFilter {
+    fn wants(&self, selection: Selection) -> bool {
+        self.formatters.contains(selection)
+            || self.reloader.sync().mode.is_synced(selection)
+            || self.reloader.persist(selection)
+    }
+
+    fn accepts(&self, selection: Selection, owner: Option<&App>) -> bool {
+        let accepted = self.reloader.source().accepts(owner);
+        if !accepted {
+            log::debug!("Ignore {} copied from {:?}", selection.name(), owner);
+        }
+        accepted
+    }
+}
+
+impl Control {
+    /// Controls `engine`, sharing its formatters, pause state and loop detection.
+    pub fn new<B: ClipboardBackend>(
+        reloader: Reloader,
+        engine: &ClipdEngine<B>,
+        waker: Waker,
+    ) -> Self {
+        Self {
+            reloader,
+            pause: engine.pause(),
+            stopping: Arc::new(AtomicBool::new(false)),
+            waker,
+            formatters: engine.formatters().clone(),
+            ping_pong: engine.ping_pong(),
+            display: Arc::new(Mutex::new(DisplayStatus {
+                name: std::env::var("DISPLAY").ok(),
+                connected: true,
+                attempts: 0,
+                error: None,
+            })),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause.is_paused()
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        log::info!("{}", if paused { "Paused" } else { "Resumed" });
+        self.pause.set_paused(paused);
+        self.waker.invalidate_renders();
+    }
+
+    pub fn toggle_pause(&self) {
+        self.set_paused(!self.is_paused());
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Makes the clipboard loop return, a write in progress is finished first.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.waker.wake();
+    }
+
+    /// Formats `text` of `selection` for `app`, which pastes it. Its `[[requestor]]` profile
+    /// replaces the formatter of the selection. Counted against what ran if `count`.
+    fn format_for(
+        &self,
+        selection: Selection,
+        app: &App,
+        text: String,
+        count: bool,
+    ) -> Result<String> {
+        let requestors = self.reloader.requestors();
+        let requestors = requestors.read().unwrap();
+        let (formatted, matched) = match requestors.iter().find(|(m, _)| m.matches(app)) {
+            Some((_, Some(formatter))) => self.formatters.run_counted(formatter, text, count)?,
+            Some((_, None)) => (Formatted::Unchanged(text), Default::default()),
+            None => self.formatters.format_counted(selection, text, count)?,
+        };
+        // Pasted as soon as it is returned.
+        self.formatters.rewrote(matched);
+        Ok(formatted.into_text())
+    }
+
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        self.reloader.reload()?;
+        self.waker.invalidate_renders();
+        Ok(())
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            pid: Some(std::process::id()),
+            uptime_secs: Some(self.started_at.elapsed().as_secs()),
+            config_path: self.reloader.config_path(),
+            rules: self.formatters.rules(),
+            display: Some(self.display.lock().unwrap().clone()),
+            ping_pong: self.ping_pong.lock().unwrap().status(Instant::now()),
+            ..Status::new(if self.is_paused() {
+                State::Paused
+            } else {
+                State::Running
+            })
+        }
+    }
+
+    fn handle(&self, request: Request) -> Response {
+        match request {
+            Request::Pause => self.set_paused(true),
+            Request::Resume => self.set_paused(false),
+            Request::Status => return Response::Status(self.status()),
+            Request::Stop => self.stop(),
+            Request::Reload => {
+                if let Err(e) = self.reload() {
+                    return Response::error(e);
+                }
+            }
+        }
+        Response::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{ClipdService, ConfigFilter, Control, Reloader, SelectionSync, Waker};
+    use crate::{
+        config::{Config, Selection},
+        engine::{
+            mock::{MockBackend, CLIPD},
+            ClipdEngine,
+        },
+    };
+
+    /// Handles every change the way [`ClipdService::run`] does, until the script is done.
+    fn run(config: &str, backend: MockBackend) -> ClipdEngine<MockBackend> {
+        let source = format!("[format]\nrules = [\"trim-end-whitespace\"]\n{}", config);
+        let config = Config::parse(Path::new("config.toml"), &source).unwrap();
+        let service = ClipdService::new(&config);
+        let filter = ConfigFilter::new(Reloader::new(&config));
+        let mut engine = ClipdEngine::new(
+            backend.with_filter(Box::new(filter.clone())),
+            filter.formatters,
+        );
+        let control = Control::new(filter.reloader, &engine, Waker::pipe().unwrap().1);
+        let mut sync = SelectionSync::new(service.reloader.sync());
+        loop {
+            match engine.wait(sync.deadline()).unwrap() {
+                Some((selection, text)) => service
+                    .handle(&mut engine, &control, &mut sync, selection, text)
+                    .unwrap(),
+                None => {
+                    let settled = sync.deadline().is_none();
+                    service
+                        .sync_settled(&mut engine, &control, &mut sync)
+                        .unwrap();
+                    if settled && engine.backend().is_done() {
+                        return engine;
+                    }
+                }
+            }
+        }
+    }
+
+    fn writes(engine: &ClipdEngine<MockBackend>) -> Vec<(Selection, &str)> {
+        let writes = engine.backend().writes().iter();
+        writes.map(|(s, t)| (*s, t.as_str())).collect()
+    }
+
+    #[test]
+    fn source() {
+        let config = "[source]\nexclude = [{ class = \"keepassxc\" }]";
+        let backend = MockBackend::new()
+            .copy(Selection::Clipboard, "KeePassXC", "secret\n")
+            .copy(Selection::Clipboard, "", "unknown\n")
+            .copy(Selection::Clipboard, "editor", "a\n");
+        let engine = run(config, backend);
+        assert_eq!(writes(&engine), [(Selection::Clipboard, "a")]);
+
+        // Without a list, apps that can not be told are formatted too.
+        let backend = MockBackend::new().copy(Selection::Clipboard, "", "unknown\n");
+        let engine = run("", backend);
+        assert_eq!(writes(&engine), [(Selection::Clipboard, "unknown")]);
+    }
+
+    #[test]
+    fn format_on_paste() {
+        let config = r#"
+[selection]
+format_on = "paste"
+
+[[requestor]]
+class = "kitty"
+"#;
+        let backend = MockBackend::new().copy(Selection::Clipboard, "editor", "a \n");
+        let engine = run(config, backend);
+        let backend = engine.backend();
+        assert_eq!(backend.paste(Selection::Clipboard, "firefox").unwrap(), "a");
+        assert_eq!(
+            backend.paste(Selection::Clipboard, "kitty").unwrap(),
+            "a \n"
+        );
+        // Counted once, for the first paste.
+        let rule = engine.formatters().rules().pop().unwrap();
+        assert_eq!((rule.seen, rule.rewritten), (1, 1));
+
+        // The profile of the app that pastes first is counted, rather than the selection's.
+        let backend = MockBackend::new().copy(Selection::Clipboard, "editor", "a \n");
+        let engine = run(config, backend);
+        let backend = engine.backend();
+        assert_eq!(
+            backend.paste(Selection::Clipboard, "kitty").unwrap(),
+            "a \n"
+        );
+        assert_eq!(backend.paste(Selection::Clipboard, "firefox").unwrap(), "a");
+        let rule = engine.formatters().rules().pop().unwrap();
+        assert_eq!((rule.seen, rule.rewritten), (0, 0));
+
+        // A copy while the deferred text is stored wins, and is deferred in turn.
+        let backend = MockBackend::new()
+            .copy(Selection::Clipboard, "editor", "a \n")
+            .copy_during_write(Selection::Clipboard, "editor", "b \n");
+        let engine = run(config, backend);
+        let backend = engine.backend();
+        assert_eq!(backend.owner(Selection::Clipboard), Some(CLIPD));
+        assert_eq!(backend.paste(Selection::Clipboard, "firefox").unwrap(), "b");
+        assert_eq!(writes(&engine), [(Selection::Clipboard, "b \n")]);
+
+        // Backends that can not tell have the text compared.
+        let backend = MockBackend::new()
+            .copy(Selection::Clipboard, "editor", "a \n")
+            .copy_after_read(Selection::Clipboard, "editor", "b \n")
+            .without_versions();
+        let engine = run(config, backend);
+        assert_eq!(writes(&engine), [(Selection::Clipboard, "b \n")]);
+    }
+
+    #[test]
+    fn persist() {
+        let config = "[selection.clipboard]\npersist = true";
+        let backend = MockBackend::new()
+            .copy(Selection::Clipboard, "editor", "a")
+            .copy(Selection::Clipboard, "editor", "b\n");
+        let engine = run(config, backend);
+        // What we wrote is ours to serve anyway.
+        assert_eq!(engine.backend().saved(Selection::Clipboard), Some("a"));
+        assert_eq!(writes(&engine), [(Selection::Clipboard, "b")]);
+
+        let backend = MockBackend::new().copy(Selection::Clipboard, "editor", "a");
+        let engine = run("", backend);
+        assert_eq!(engine.backend().saved(Selection::Clipboard), None);
+    }
+
+    #[test]
+    fn mirror() {
+        let config = "[sync]\nmode = \"clipboard-to-primary\"";
+        let backend = MockBackend::new()
+            .copy(Selection::Clipboard, "editor", "a\n")
+            .copy(Selection::Primary, "terminal", "c")
+            .copy(Selection::Clipboard, "editor", "a")
+            .without_echo();
+        let engine = run(config, backend);
+        // Selecting replaced the mirrored copy, so the same copy is mirrored again.
+        assert_eq!(
+            writes(&engine),
+            [
+                (Selection::Clipboard, "a"),
+                (Selection::Primary, "a"),
+                (Selection::Primary, "a")
+            ]
+        );
+    }
+
+    #[test]
+    fn sync_settled() {
+        let config = "[sync]\nmode = \"primary-to-clipboard\"\nsettle_ms = 0";
+        let backend = MockBackend::new()
+            .copy(Selection::Primary, "terminal", "a")
+            .quiet()
+            .selecting(1)
+            .without_echo();
+        let engine = run(config, backend);
+        // Read again once the user let go.
+        assert_eq!(writes(&engine), [(Selection::Clipboard, "a")]);
+        assert_eq!(engine.backend().reads(), 2);
+
+        let backend = MockBackend::new().copy(Selection::Primary, "terminal", "a");
+        let engine = run("", backend);
+        assert!(writes(&engine).is_empty());
+    }
+}
@@ -0,0 +1,31 @@
+use anyhow::Result;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1},
+    iterator::Signals,
+};
+
+use super::service::Control;
+
+/// `SIGHUP` reloads the configuration, `SIGUSR1` toggles pause, `SIGINT` and `SIGTERM` stop the
+/// service. A second `SIGINT` or `SIGTERM` exits at once.
+pub fn listen(control: Control) -> Result<()> {
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM, SIGUSR1])?;
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            log::debug!("Signal: {}", signal);
+            match signal {
+                SIGHUP => {
+                    let _ = control.reload();
+                }
+                SIGUSR1 => control.toggle_pause(),
+                SIGINT | SIGTERM if control.is_stopping() => {
+                    log::warn!("Exit without cleanup");
+                    std::process::exit(128 + signal);
+                }
+                SIGINT | SIGTERM => control.stop(),
+                _ => (),
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use std::time::Instant;
+
+use crate::config::{Selection, SyncConfig};
+
+/// Decides when text is mirrored between PRIMARY and CLIPBOARD.
+///
+/// CLIPBOARD is mirrored at once. PRIMARY changes while text is being dragged over, so it is only
+/// mirrored once it settled and is read again at that point. Text equal to the last mirrored one
+/// is never mirrored back, which stops ping-pong with other clipboard tools.
+#[derive(Debug)]
+pub struct SelectionSync {
+    config: SyncConfig,
+    pending: Option<(Selection, Instant)>,
+    /// The selection we mirrored to last and the text it got, until it changes.
+    last: Option<(Selection, String)>,
+}
+
+impl SelectionSync {
+    pub fn new(config: SyncConfig) -> Self {
+        Self {
+            config,
+            pending: None,
+            last: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: SyncConfig) {
+        if self.config != config {
+            log::debug!("Sync: {:?}", config);
+            self.config = config;
+            self.pending = None;
+        }
+    }
+
+    /// `from` now holds `text`, returns the selection to mirror it to right away.
+    pub fn changed(&mut self, from: Selection, text: &str, now: Instant) -> Option<Selection> {
+        if from == Selection::Clipboard {
+            // An explicit copy wins over a selection that has not settled yet.
+            self.pending = None;
+        }
+        match &self.last {
+            Some((_, last)) if last == text => {
+                log::trace!("Sync: {} already mirrored", from.name());
+                return None;
+            }
+            // The mirrored text is gone, copying it again has to be mirrored again.
+            Some((to, _)) if *to == from => self.last = None,
+            _ => {}
+        }
+        let to = self.config.mode.target(from)?;
+        self.last = None;
+        if from == Selection::Primary {
+            self.pending = Some((from, now + self.config.settle));
+            return None;
+        }
+        Some(to)
+    }
+
+    /// When [`SelectionSync::due`] has something to do.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.map(|(_, deadline)| deadline)
+    }
+
+    /// The settled `(from, to)` pair, `from` has to be read again before mirroring.
+    pub fn due(&mut self, now: Instant) -> Option<(Selection, Selection)> {
+        match self.pending {
+            Some((from, deadline)) if deadline <= now => {
+                self.pending = None;
+                self.config.mode.target(from).map(|to| (from, to))
+            }
+            _ => None,
+        }
+    }
+
+    /// A drag is still in progress, try again later.
+    pub fn postpone(&mut self, from: Selection, now: Instant) {
+        self.pending = Some((from, now + self.config.settle));
+    }
+
+    /// Whether `text` should be written to `to`, it is remembered as mirrored if so.
+    pub fn mirror(&mut self, to: Selection, text: &str) -> bool {
+        if text.is_empty() || self.last.as_ref().is_some_and(|(_, last)| last == text) {
+            return false;
+        }
+        self.last = Some((to, text.to_owned()));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::SelectionSync;
+    use crate::config::{Selection, SyncConfig, SyncMode};
+
+    fn sync(mode: SyncMode) -> SelectionSync {
+        SelectionSync::new(SyncConfig {
+            mode,
+            settle: Duration::from_millis(100),
+        })
+    }
+
+    #[test]
+    fn clipboard_to_primary() {
+        let mut sync = sync(SyncMode::Both);
+        let now = Instant::now();
+        assert_eq!(
+            sync.changed(Selection::Clipboard, "a", now),
+            Some(Selection::Primary)
+        );
+        assert!(sync.mirror(Selection::Primary, "a"));
+        assert!(!sync.mirror(Selection::Primary, "a"));
+        // Someone else sets PRIMARY to what we wrote.
+        assert_eq!(sync.changed(Selection::Primary, "a", now), None);
+        assert_eq!(sync.deadline(), None);
+        // SECONDARY is not synced, the mirrored text is still in place.
+        assert_eq!(sync.changed(Selection::Secondary, "b", now), None);
+        assert_eq!(sync.changed(Selection::Clipboard, "a", now), None);
+        assert_eq!(
+            sync.changed(Selection::Clipboard, "b", now),
+            Some(Selection::Primary)
+        );
+    }
+
+    #[test]
+    fn primary_settles() {
+        let mut sync = sync(SyncMode::PrimaryToClipboard);
+        let now = Instant::now();
+        assert_eq!(sync.changed(Selection::Primary, "a", now), None);
+        assert_eq!(sync.changed(Selection::Primary, "ab", now), None);
+        assert_eq!(sync.deadline(), Some(now + Duration::from_millis(100)));
+        assert_eq!(sync.due(now), None);
+
+        let later = now + Duration::from_millis(100);
+        assert_eq!(
+            sync.due(later),
+            Some((Selection::Primary, Selection::Clipboard))
+        );
+        assert_eq!(sync.due(later), None);
+
+        sync.postpone(Selection::Primary, later);
+        assert_eq!(sync.deadline(), Some(later + Duration::from_millis(100)));
+        assert_eq!(sync.changed(Selection::Clipboard, "x", later), None);
+        assert_eq!(sync.deadline(), None);
+    }
+
+    #[test]
+    fn target_change_resets_guard() {
+        let mut sync = sync(SyncMode::ClipboardToPrimary);
+        let now = Instant::now();
+        assert_eq!(
+            sync.changed(Selection::Clipboard, "a", now),
+            Some(Selection::Primary)
+        );
+        assert!(sync.mirror(Selection::Primary, "a"));
+        // PRIMARY is not mirrored from, but selecting there replaces our copy.
+        assert_eq!(sync.changed(Selection::Primary, "c", now), None);
+        assert_eq!(
+            sync.changed(Selection::Clipboard, "a", now),
+            Some(Selection::Primary)
+        );
+        assert!(sync.mirror(Selection::Primary, "a"));
+    }
+
+    #[test]
+    fn off() {
+        let mut sync = sync(SyncMode::Off);
+        let now = Instant::now();
+        assert_eq!(sync.changed(Selection::Clipboard, "a", now), None);
+        assert_eq!(sync.changed(Selection::Primary, "a", now), None);
+        assert_eq!(sync.deadline(), None);
+
+        sync.set_config(SyncConfig {
+            mode: SyncMode::ClipboardToPrimary,
+            settle: Duration::ZERO,
+        });
+        assert_eq!(
+            sync.changed(Selection::Clipboard, "a", now),
+            Some(Selection::Primary)
+        );
+    }
+}
@@ -0,0 +1,364 @@
+//! Text targets of X11 selections and their encodings.
+
+use encoding_rs::{Encoding, EUC_JP, EUC_KR, GBK, UTF_8};
+
+/// Encoding of the data of a text target or property type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextType {
+    /// `UTF8_STRING` or `text/plain;charset=utf-8`.
+    Utf8,
+    /// `text/plain;charset=...`, the owner flagged the encoding itself.
+    Mime(&'static Encoding),
+    CompoundText,
+    /// Any of the others, the reply type tells which.
+    Text,
+    /// Latin-1 by ICCCM, some apps put their locale encoding there though.
+    String,
+}
+
+impl TextType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "UTF8_STRING" => TextType::Utf8,
+            "COMPOUND_TEXT" => TextType::CompoundText,
+            "TEXT" => TextType::Text,
+            "STRING" => TextType::String,
+            _ => {
+                let (mime, charset) = name.split_once(';')?;
+                let charset = charset.trim().strip_prefix("charset=")?;
+                if !mime.trim().eq_ignore_ascii_case("text/plain") {
+                    return None;
+                }
+                match Encoding::for_label(charset.trim_matches('"').as_bytes())? {
+                    e if e == UTF_8 => TextType::Utf8,
+                    e => TextType::Mime(e),
+                }
+            }
+        })
+    }
+
+    /// Lower is better, lossless targets first.
+    fn rank(&self) -> u8 {
+        match self {
+            TextType::Utf8 => 0,
+            TextType::Mime(_) => 1,
+            TextType::CompoundText => 2,
+            TextType::Text => 3,
+            TextType::String => 4,
+        }
+    }
+
+    /// Decodes data whose property type is `self`.
+    ///
+    /// `string_encoding` replaces Latin-1 for `STRING`.
+    pub fn decode(&self, data: &[u8], string_encoding: Option<&'static Encoding>) -> String {
+        match self {
+            TextType::Utf8 => String::from_utf8_lossy(data).into_owned(),
+            TextType::Mime(encoding) => encoding.decode_without_bom_handling(data).0.into_owned(),
+            TextType::CompoundText => decode_compound_text(data),
+            // `TEXT` is not a valid reply type, treat it like `STRING`.
+            TextType::Text | TextType::String => match string_encoding {
+                Some(encoding) => encoding.decode_without_bom_handling(data).0.into_owned(),
+                None => decode_latin1(data),
+            },
+        }
+    }
+}
+
+/// The best text target among `targets`, `(target, name)` pairs as listed by `TARGETS`.
+pub fn best_target<'a, T>(
+    targets: impl IntoIterator<Item = (T, &'a str)>,
+) -> Option<(T, TextType)> {
+    targets
+        .into_iter()
+        .filter_map(|(target, name)| TextType::from_name(name).map(|t| (target, t)))
+        .min_by_key(|(_, t)| t.rank())
+}
+
+/// Whether the target `name` is plain text in some encoding.
+pub fn is_plain_text(name: &str) -> bool {
+    name == "text/plain" || TextType::from_name(name).is_some()
+}
+
+/// `text` as `STRING`, `None` if it has characters the encoding lacks.
+pub fn encode_string(text: &str, string_encoding: Option<&'static Encoding>) -> Option<Vec<u8>> {
+    match string_encoding {
+        Some(encoding) => {
+            let (data, used, had_errors) = encoding.encode(text);
+            // Encoders of UTF-16 and replacement fall back to UTF-8.
+            (used == encoding && !had_errors).then(|| data.into_owned())
+        }
+        None => text.chars().map(|c| u8::try_from(c).ok()).collect(),
+    }
+}
+
+/// `text` as `COMPOUND_TEXT`: Latin-1 as is, anything else in a UTF-8 segment.
+pub fn encode_compound_text(text: &str) -> Vec<u8> {
+    match encode_string(text, None) {
+        Some(latin1) => latin1,
+        None => [b"\x1b%G", text.as_bytes(), b"\x1b%@"].concat(),
+    }
+}
+
+fn decode_latin1(data: &[u8]) -> String {
+    data.iter().map(|b| char::from(*b)).collect()
+}
+
+/// A character set designated to GL or GR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Charset {
+    Ascii,
+    Latin1,
+    /// A 96 character set, decoded from GR bytes.
+    Single(&'static Encoding),
+    /// A 94^2 character set, decoded from its EUC form.
+    Double(&'static Encoding),
+    Unknown,
+}
+
+impl Charset {
+    fn single(final_byte: u8) -> Self {
+        let encoding = match final_byte {
+            b'A' => return Charset::Latin1,
+            b'B' => encoding_rs::ISO_8859_2,
+            b'C' => encoding_rs::ISO_8859_3,
+            b'D' => encoding_rs::ISO_8859_4,
+            b'F' => encoding_rs::ISO_8859_7,
+            b'G' => encoding_rs::ISO_8859_6,
+            b'H' => encoding_rs::ISO_8859_8,
+            b'L' => encoding_rs::ISO_8859_5,
+            b'M' => encoding_rs::WINDOWS_1254,
+            b'b' => encoding_rs::ISO_8859_15,
+            _ => return Charset::Unknown,
+        };
+        Charset::Single(encoding)
+    }
+
+    fn double(final_byte: u8) -> Self {
+        match final_byte {
+            // GB 2312 is a subset of GBK.
+            b'A' => Charset::Double(GBK),
+            b'B' => Charset::Double(EUC_JP),
+            b'C' => Charset::Double(EUC_KR),
+            _ => Charset::Unknown,
+        }
+    }
+}
+
+/// Collects decoded text, consecutive bytes of one encoding are decoded together.
+#[derive(Default)]
+struct Output {
+    text: String,
+    run: Vec<u8>,
+    encoding: Option<&'static Encoding>,
+}
+
+impl Output {
+    fn push(&mut self, c: char) {
+        self.flush();
+        self.text.push(c);
+    }
+
+    fn push_encoded(&mut self, encoding: &'static Encoding, data: &[u8]) {
+        if self.encoding != Some(encoding) {
+            self.flush();
+            self.encoding = Some(encoding);
+        }
+        self.run.extend_from_slice(data);
+    }
+
+    fn flush(&mut self) {
+        if let Some(encoding) = self.encoding.take() {
+            let (text, _) = encoding.decode_without_bom_handling(&self.run);
+            self.text.push_str(&text);
+            self.run.clear();
+        }
+    }
+}
+
+/// Decodes the parts of `COMPOUND_TEXT` (X11 Compound Text Encoding) apps use in practice:
+/// ISO 8859 and the CJK 94^2 sets, UTF-8 segments and extended segments naming their encoding.
+/// Other character sets become U+FFFD.
+fn decode_compound_text(data: &[u8]) -> String {
+    let (mut gl, mut gr) = (Charset::Ascii, Charset::Latin1);
+    let mut out = Output::default();
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        i += 1;
+        match b {
+            0x1b => {
+                let start = i;
+                while i < data.len() && (0x20..=0x2f).contains(&data[i]) {
+                    i += 1;
+                }
+                let (intermediates, final_byte) = match data.get(i) {
+                    Some(f) => (&data[start..i], *f),
+                    None => break,
+                };
+                i += 1;
+                match (intermediates, final_byte) {
+                    (b"(", b'B' | b'J') => gl = Charset::Ascii,
+                    // Only JIS X 0201 is left, nobody sends it.
+                    (b"(", _) => gl = Charset::Unknown,
+                    (b")", _) => gr = Charset::Unknown,
+                    (b"-", f) => gr = Charset::single(f),
+                    (b"$" | b"$(", f) => gl = Charset::double(f),
+                    (b"$)", f) => gr = Charset::double(f),
+                    (b"%", b'G') => {
+                        let end = find(&data[i..], b"\x1b%@").map_or(data.len(), |n| i + n);
+                        out.push_encoded(UTF_8, &data[i..end]);
+                        i = (end + 3).min(data.len());
+                    }
+                    (b"%/", b'0'..=b'4') => {
+                        let segment = match data.get(i..i + 2) {
+                            Some(&[m, l]) if m >= 0x80 && l >= 0x80 => {
+                                let len = (m as usize - 0x80) * 0x80 + (l as usize - 0x80);
+                                i += 2;
+                                &data[i..(i + len).min(data.len())]
+                            }
+                            _ => break,
+                        };
+                        i += segment.len();
+                        extended_segment(&mut out, segment);
+                    }
+                    _ => log::trace!("Ignore escape {:?} {}", intermediates, final_byte),
+                }
+            }
+            // Control sequences only set the direction.
+            0x9b => {
+                while i < data.len() && !(0x40..=0x7e).contains(&data[i]) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'\n' | b'\t' | b' ' => out.push(char::from(b)),
+            0x21..=0x7e => decode_byte(&mut out, gl, b, data.get(i), &mut i),
+            0xa0..=0xff => decode_byte(&mut out, gr, b, data.get(i), &mut i),
+            _ => {}
+        }
+    }
+    out.flush();
+    out.text
+}
+
+/// Decodes `b` of `charset`, the second byte of a 94^2 set is `next`.
+fn decode_byte(out: &mut Output, charset: Charset, b: u8, next: Option<&u8>, i: &mut usize) {
+    match charset {
+        Charset::Ascii => out.push(char::from(b & 0x7f)),
+        Charset::Latin1 => out.push(char::from(b | 0x80)),
+        Charset::Single(encoding) => out.push_encoded(encoding, &[b | 0x80]),
+        Charset::Double(encoding) => match next {
+            Some(next) if *next & 0x7f > 0x20 => {
+                *i += 1;
+                out.push_encoded(encoding, &[b | 0x80, next | 0x80]);
+            }
+            _ => out.push(char::REPLACEMENT_CHARACTER),
+        },
+        Charset::Unknown => out.push(char::REPLACEMENT_CHARACTER),
+    }
+}
+
+/// `name STX data` in the encoding `name` flags, e.g. `gb18030-0`.
+fn extended_segment(out: &mut Output, segment: &[u8]) {
+    let (name, data) = match find(segment, b"\x02") {
+        Some(n) => (&segment[..n], &segment[n + 1..]),
+        None => return out.push(char::REPLACEMENT_CHARACTER),
+    };
+    // X11 names end with the registry's encoding number.
+    let label = name.strip_suffix(b"-0").unwrap_or(name);
+    match Encoding::for_label(label) {
+        Some(encoding) => out.push_encoded(encoding, data),
+        None => {
+            log::debug!(
+                "Unknown segment encoding {:?}",
+                String::from_utf8_lossy(name)
+            );
+            out.push(char::REPLACEMENT_CHARACTER)
+        }
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{GB18030, GBK};
+
+    use super::{best_target, encode_compound_text, encode_string, is_plain_text, TextType};
+
+    #[test]
+    fn targets() {
+        let targets = [
+            (1, "TARGETS"),
+            (2, "STRING"),
+            (3, "COMPOUND_TEXT"),
+            (4, "TEXT"),
+        ];
+        assert_eq!(best_target(targets), Some((3, TextType::CompoundText)));
+        assert_eq!(best_target([(1, "TARGETS"), (2, "image/png")]), None);
+        assert_eq!(
+            best_target([(1, "STRING"), (2, "text/plain;charset=GB18030")]),
+            Some((2, TextType::Mime(GB18030)))
+        );
+        assert_eq!(
+            best_target([
+                (1, "text/plain;charset=gbk"),
+                (2, "text/plain;charset=utf-8"),
+                (3, "UTF8_STRING"),
+            ]),
+            Some((2, TextType::Utf8))
+        );
+        assert_eq!(TextType::from_name("text/html;charset=utf-8"), None);
+        assert_eq!(TextType::from_name("text/plain;charset=nope"), None);
+        assert!(is_plain_text("text/plain"));
+        assert!(is_plain_text("TEXT"));
+        assert!(!is_plain_text("text/html"));
+    }
+
+    #[test]
+    fn string() {
+        assert_eq!(TextType::String.decode(b"caf\xe9", None), "café");
+        assert_eq!(
+            TextType::Text.decode(b"\xd6\xd0\xce\xc4", Some(GBK)),
+            "中文"
+        );
+        assert_eq!(
+            TextType::Mime(GB18030).decode(b"\x81\x30\x81\x30", None),
+            "\u{80}"
+        );
+        assert_eq!(encode_string("café", None), Some(b"caf\xe9".to_vec()));
+        assert_eq!(encode_string("中文", None), None);
+        assert_eq!(
+            encode_string("中文", Some(GBK)),
+            Some(b"\xd6\xd0\xce\xc4".to_vec())
+        );
+        assert_eq!(encode_string("😀", Some(GBK)), None);
+    }
+
+    #[test]
+    fn compound_text() {
+        let decode = |data: &[u8]| TextType::CompoundText.decode(data, None);
+        assert_eq!(decode(b"a\tb\ncaf\xe9"), "a\tb\ncafé");
+        // GB 2312 in GR, then in GL, then back to ASCII.
+        assert_eq!(
+            decode(b"x\x1b$)A\xd6\xd0\x1b$(A\x56\x50\x4e\x44\x1b(By"),
+            "x中中文y"
+        );
+        assert_eq!(decode(b"\x1b-L\xbf\xe0"), "Пр");
+        assert_eq!(decode(b"\x1b%G\xe4\xb8\xad\x1b%@!"), "中!");
+        // An extended segment of 14 bytes: the name, STX and 4 bytes of GB 18030.
+        assert_eq!(
+            decode(b"\x1b%/2\x80\x8egb18030-0\x02\x81\x30\x81\x30."),
+            "\u{80}."
+        );
+        assert_eq!(decode(b"\x1b$(Z\x21\x21\x1b(Ba"), "\u{fffd}\u{fffd}a");
+        assert_eq!(decode(b"\x9b2]a\x9b]"), "a");
+
+        for text in ["plain", "café", "中文 ok"] {
+            assert_eq!(decode(&encode_compound_text(text)), text);
+        }
+    }
+}
@@ -1,12 +1,27 @@
-use std::mem::transmute;
+use std::{
+    mem::transmute,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, bail, Result};
 use objc::runtime::{Class, Object};
 use objc_foundation::{INSArray, INSObject, INSString, NSArray, NSDictionary, NSObject, NSString};
 use objc_id::Id;
 
+use crate::{
+    config::Selection,
+    engine::{ClipboardBackend, WaitingBackend},
+};
+
+/// The pasteboard has no change notifications, its change count is polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
 pub struct OSXClipboard {
     pasteboard: Id<Object>,
+    /// Change count of the pasteboard when it was last looked at.
+    change_count: isize,
+    /// Change count of the pasteboard when it was last read.
+    read_count: Option<isize>,
 }
 
 // required to bring NSPasteboard into the path of the class-resolver
@@ -22,7 +37,16 @@ impl OSXClipboard {
             bail!("Failed to generalPasteboard")
         }
         let pasteboard = unsafe { Id::from_ptr(pasteboard) };
-        Ok(Self { pasteboard })
+        // What is on the pasteboard at start is formatted as well.
+        Ok(Self {
+            pasteboard,
+            change_count: -1,
+            read_count: None,
+        })
+    }
+
+    pub fn change_count(&self) -> isize {
+        unsafe { msg_send![self.pasteboard, changeCount] }
     }
 
     pub fn get_text(&self) -> Result<Option<String>> {
@@ -57,6 +81,42 @@ impl OSXClipboard {
     }
 }
 
+impl ClipboardBackend for OSXClipboard {
+    fn read_text(&mut self, _selection: Selection) -> Result<Option<String>> {
+        self.read_count = Some(self.change_count());
+        self.get_text()
+    }
+
+    fn write_text(&mut self, _selection: Selection, text: String) -> Result<bool> {
+        self.set_text(text)?;
+        Ok(true)
+    }
+
+    fn is_unchanged(&mut self, _selection: Selection) -> Result<Option<bool>> {
+        Ok(self.read_count.map(|count| count == self.change_count()))
+    }
+}
+
+impl WaitingBackend for OSXClipboard {
+    fn wait_for_change(&mut self, deadline: Option<Instant>) -> Result<Option<Selection>> {
+        loop {
+            let change_count = self.change_count();
+            if change_count != self.change_count {
+                self.change_count = change_count;
+                return Ok(Some(Selection::Clipboard));
+            }
+            let timeout = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => POLL_INTERVAL,
+            };
+            if timeout.is_zero() {
+                return Ok(None);
+            }
+            std::thread::sleep(timeout.min(POLL_INTERVAL));
+        }
+    }
+}
+
 // this is a convenience function that both cocoa-rs and
 //  glutin define, which seems to depend on the fact that
 //  Option::None has the same representation as a null pointer
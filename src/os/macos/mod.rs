@@ -1,17 +1,19 @@
 mod clipboard;
-mod fmt;
 mod service;
 
 use anyhow::Result;
 
 use super::OsAbstractionLayer;
-use crate::os_stub;
+use crate::{config::Config, os_stub};
 
-#[derive(Debug)]
-pub struct MacOAL;
+#[derive(Debug, Default)]
+pub struct MacOAL {
+    config: Config,
+}
 
 impl OsAbstractionLayer for MacOAL {
-    fn init(&mut self, _args: &crate::Args) -> Result<()> {
+    fn init(&mut self, _args: &crate::Args, config: Config) -> Result<()> {
+        self.config = config;
         Ok(())
     }
 
@@ -19,16 +21,10 @@ impl OsAbstractionLayer for MacOAL {
         if daemon {
             os_stub!()
         }
-        service::ClipdService::new()?.run()
+        service::ClipdService::new(&self.config)?.run()
     }
 
     fn service_controller(&self) -> Result<Box<dyn super::SystemServiceController>> {
         os_stub!()
     }
 }
-
-impl Default for MacOAL {
-    fn default() -> Self {
-        Self
-    }
-}
@@ -2,7 +2,7 @@ use std::ffi::OsString;
 
 use anyhow::Result;
 
-use crate::Args;
+use crate::{config::Config, ipc::Status, Args};
 
 pub trait SystemServiceController {
     fn install(&self, arguments: Vec<OsString>) -> Result<()>;
@@ -10,13 +10,17 @@ pub trait SystemServiceController {
     fn pause(&self) -> Result<()>;
     fn resume(&self) -> Result<()>;
     fn stop(&self) -> Result<()>;
-    fn status(&self) -> Result<()>;
+    fn status(&self) -> Result<Status>;
+    /// Reloads the configuration of the running service.
+    fn reload(&self) -> Result<()> {
+        crate::os_stub!()
+    }
     fn restart(&self, arguments: Vec<OsString>) -> Result<()>;
     fn uninstall(&self) -> Result<()>;
 }
 
 pub trait OsAbstractionLayer: Send + Sync + 'static {
-    fn init(&mut self, args: &Args) -> Result<()>;
+    fn init(&mut self, args: &Args, config: Config) -> Result<()>;
     fn run_clipd(&self, daemon: bool) -> Result<()>;
     fn service_controller(&self) -> Result<Box<dyn SystemServiceController>>;
 }
@@ -28,27 +32,28 @@ macro_rules! os_stub {
     };
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct OAL;
 
 impl OAL {
     #[cfg(target_os = "windows")]
-    pub fn init(args: &Args) -> Result<Box<dyn OsAbstractionLayer>> {
+    pub fn init(args: &Args, config: Config) -> Result<Box<dyn OsAbstractionLayer>> {
         let mut oal = Box::new(super::windows::WindowsOAL::default());
-        oal.init(args)?;
+        oal.init(args, config)?;
         Ok(oal)
     }
 
     #[cfg(target_os = "linux")]
-    pub fn init(args: &Args) -> Result<Box<dyn OsAbstractionLayer>> {
+    pub fn init(args: &Args, config: Config) -> Result<Box<dyn OsAbstractionLayer>> {
         let mut oal = Box::new(super::linux::LinuxOAL::default());
-        oal.init(args)?;
+        oal.init(args, config)?;
         Ok(oal)
     }
 
     #[cfg(target_os = "macos")]
-    pub fn init(args: &Args) -> Result<Box<dyn OsAbstractionLayer>> {
+    pub fn init(args: &Args, config: Config) -> Result<Box<dyn OsAbstractionLayer>> {
         let mut oal = Box::new(super::macos::MacOAL::default());
-        oal.init(args)?;
+        oal.init(args, config)?;
         Ok(oal)
     }
 }
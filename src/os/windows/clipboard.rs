@@ -6,7 +6,7 @@ use windows::{
         System::{
             DataExchange::{
                 AddClipboardFormatListener, CloseClipboard, EmptyClipboard, GetClipboardData,
-                GetClipboardOwner, IsClipboardFormatAvailable, OpenClipboard,
+                GetClipboardSequenceNumber, IsClipboardFormatAvailable, OpenClipboard,
                 RemoveClipboardFormatListener, SetClipboardData,
             },
             Memory::{GlobalLock, GlobalUnlock},
@@ -17,23 +17,23 @@ use windows::{
 };
 
 use crate::{
-    fmt::{FormatFeature, FormatResult, Formatter, StringFormatter},
-    os::windows::mem::HandleGuard,
-    ExpectWithTracing,
+    config::Selection, engine::ClipboardBackend, os::windows::mem::HandleGuard, ExpectWithTracing,
 };
 
+/// The clipboard, whose changes are posted to `window` as `WM_CLIPBOARDUPDATE`.
 #[derive(Debug)]
-pub struct ClipboardFormatter {
-    utf16_formatter: HANDLE2UTF16Formatter,
+pub struct WindowsClipboard {
     window: HWND,
+    /// Clipboard sequence number when it was last read.
+    read_sequence: Option<u32>,
 }
 
-impl ClipboardFormatter {
+impl WindowsClipboard {
     pub fn new(window: HWND) -> Self {
         unsafe { AddClipboardFormatListener(window).expectx("AddClipboardFormatListener") };
         Self {
             window,
-            utf16_formatter: HANDLE2UTF16Formatter::default(),
+            read_sequence: None,
         }
     }
 
@@ -42,12 +42,7 @@ impl ClipboardFormatter {
         unsafe { RemoveClipboardFormatListener(window).expectx("RemoveClipboardFormatListener") };
     }
 
-    pub unsafe fn fmt(&self) -> Result<()> {
-        let owner = GetClipboardOwner();
-        if owner.eq(&self.window) {
-            return Ok(());
-        }
-
+    unsafe fn get_text(&self) -> Result<Option<String>> {
         let clipboard = Clipboard::open(self.window)?;
 
         if clipboard.is_available(CF_HDROP) {
@@ -64,23 +59,47 @@ impl ClipboardFormatter {
                     String::from_utf16(buf).unwrap()
                 );
             }
-            return Ok(());
+            return Ok(None);
         }
 
         if !clipboard.is_available(CF_UNICODETEXT) {
             log::debug!("The clipboard content format is not {:?}", CF_UNICODETEXT);
-            return Ok(());
+            return Ok(None);
         }
 
-        let text = clipboard.get_data(CF_UNICODETEXT)?;
-        let fmt_result = self.utf16_formatter.fmt(&text)?;
-        if fmt_result.has_changed() {
-            clipboard.set_text(CF_UNICODETEXT, fmt_result.data)?;
-        } else {
-            log::debug!("No text need formatting");
-        }
+        let hmem = clipboard.get_data(CF_UNICODETEXT)?.0;
+        let ptr = GlobalLock(hmem);
+        let text = PCWSTR::from_raw(ptr as _).to_string();
+        GlobalUnlock(hmem);
+        Ok(Some(text?))
+    }
 
-        Ok(())
+    unsafe fn set_text(&self, text: String) -> Result<()> {
+        let clipboard = Clipboard::open(self.window)?;
+        let text: Vec<u16> = text.encode_utf16().chain([0]).collect();
+        clipboard.set_text(CF_UNICODETEXT, text)
+    }
+}
+
+/// Changes are posted to the window, the window procedure hands them to
+/// [`ClipdEngine::on_change`](crate::engine::ClipdEngine::on_change).
+impl ClipboardBackend for WindowsClipboard {
+    fn read_text(&mut self, _selection: Selection) -> Result<Option<String>> {
+        self.read_sequence = Some(unsafe { GetClipboardSequenceNumber() });
+        unsafe { self.get_text() }
+    }
+
+    fn write_text(&mut self, _selection: Selection, text: String) -> Result<bool> {
+        unsafe { self.set_text(text)? };
+        Ok(true)
+    }
+
+    fn is_unchanged(&mut self, _selection: Selection) -> Result<Option<bool>> {
+        // Zero when the sequence number is not available to us.
+        Ok(self
+            .read_sequence
+            .filter(|sequence| *sequence != 0)
+            .map(|sequence| sequence == unsafe { GetClipboardSequenceNumber() }))
     }
 }
 
@@ -142,35 +161,3 @@ impl Drop for Clipboard {
         unsafe { CloseClipboard() };
     }
 }
-
-#[derive(Debug)]
-struct HANDLE2UTF16Formatter {
-    inner: StringFormatter,
-}
-
-impl Formatter<HANDLE, Vec<u16>> for HANDLE2UTF16Formatter {
-    fn new(feature: FormatFeature) -> Result<Self> {
-        Ok(Self {
-            inner: StringFormatter::new(feature)?.ends_with_zero(),
-        })
-    }
-
-    fn fmt(&self, text: &HANDLE) -> Result<FormatResult<Vec<u16>>> {
-        let hmem = text.0;
-        let ptr = unsafe { GlobalLock(hmem) };
-        let text = PCWSTR::from_raw(ptr as _);
-        log::trace!("{:?}", self.inner);
-        let text = unsafe { text.to_string()? };
-        log::trace!("{:?}", text);
-        let fmt_result = self.inner.fmt(&text)?;
-        log::trace!("{:?}", fmt_result);
-        unsafe { GlobalUnlock(hmem) };
-        Ok(fmt_result.map(|s| s.encode_utf16().collect()))
-    }
-}
-
-impl Default for HANDLE2UTF16Formatter {
-    fn default() -> Self {
-        Self::new_unchecked(Default::default())
-    }
-}
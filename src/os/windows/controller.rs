@@ -15,6 +15,7 @@ use windows_service::{
 };
 
 use super::SystemServiceController;
+use crate::ipc::{State, Status};
 
 pub struct WindowsServiceController {
     service_type: ServiceType,
@@ -183,7 +184,7 @@ impl SystemServiceController for WindowsServiceController {
         Ok(())
     }
 
-    fn status(&self) -> Result<()> {
+    fn status(&self) -> Result<Status> {
         let server_name = &self.service_name;
         let manager_access = ServiceManagerAccess::CONNECT;
         let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
@@ -202,9 +203,20 @@ impl SystemServiceController for WindowsServiceController {
         };
 
         let status = service.query_status()?;
-        println!("{:?}", status.current_state);
+        let state = match status.current_state {
+            ServiceState::Stopped => State::Stopped,
+            ServiceState::StartPending => State::StartPending,
+            ServiceState::StopPending => State::StopPending,
+            ServiceState::Running => State::Running,
+            ServiceState::ContinuePending => State::ContinuePending,
+            ServiceState::PausePending => State::PausePending,
+            ServiceState::Paused => State::Paused,
+        };
 
-        Ok(())
+        Ok(Status {
+            pid: status.process_id,
+            ..Status::new(state)
+        })
     }
 
     fn uninstall(&self) -> Result<()> {
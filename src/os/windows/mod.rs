@@ -1,7 +1,7 @@
+mod clipboard;
 mod controller;
 mod daemon;
 mod error;
-mod fmt;
 mod icon;
 mod mem;
 mod mutex;
@@ -19,7 +19,7 @@ use windows::{core::PCWSTR, w};
 use windows_service::service::ServiceType;
 
 use super::oal::*;
-use crate::{Args, ExpectWithTracing, SubCommand, SERVICE_NAME};
+use crate::{config::Config, Args, ExpectWithTracing, SubCommand, SERVICE_NAME};
 
 const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
 
@@ -38,10 +38,12 @@ pub struct WindowsOAL {
     service_description: String,
     service_uninstall_timeout: u64,
     executable_path: PathBuf,
+    config: Config,
 }
 
 impl OsAbstractionLayer for WindowsOAL {
-    fn init(&mut self, args: &Args) -> Result<()> {
+    fn init(&mut self, args: &Args, config: Config) -> Result<()> {
+        self.config = config;
         if let Some(SubCommand::Uninstall(args)) = &args.sub {
             self.service_uninstall_timeout = args.timeout;
         }
@@ -53,7 +55,7 @@ impl OsAbstractionLayer for WindowsOAL {
             daemon::DaemonClipdServiceDispatcher::run(self.service_name.as_str());
             Ok(())
         } else {
-            user::UserClipdServiceDispatcher::run(self.service_name.as_str())
+            user::UserClipdServiceDispatcher::run(self.service_name.as_str(), &self.config)
         }
     }
 
@@ -76,6 +78,7 @@ impl Default for WindowsOAL {
             service_description: SERVICE_NAME.into(),
             service_uninstall_timeout: 5,
             executable_path: current_exe_path(),
+            config: Config::default(),
         }
     }
 }
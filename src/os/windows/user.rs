@@ -32,12 +32,14 @@ use windows::{
 use windows_service::service::ServiceState;
 
 use super::{
-    fmt::ClipboardFormatter,
+    clipboard::WindowsClipboard,
     mutex::create_app_mutex,
     window::{self, Window},
     CLIPD_MUTEX_NAME, WM_NOTITY_ICON_REBUILD,
 };
 use crate::{
+    config::{Config, Selection},
+    engine::{ClipdEngine, Formatters},
     os::windows::{pipe::Pipe, MESSAGE_EXIT, MESSAGE_PAUSE, MESSAGE_RESUME, PIPE},
     ExpectWithTracing,
 };
@@ -51,8 +53,8 @@ const WM_TRAY_ICON: u32 = WM_USER + 0x54;
 pub struct UserClipdServiceDispatcher;
 
 impl UserClipdServiceDispatcher {
-    pub fn run(name: &str) -> Result<()> {
-        let service = UserClipdService::new(name)?;
+    pub fn run(name: &str, config: &Config) -> Result<()> {
+        let service = UserClipdService::new(name, config)?;
         let service = Arc::new(Mutex::new(service));
         let service_mutex = service.clone();
         unsafe { SERVICE.set(service).unwrap() };
@@ -79,8 +81,9 @@ struct UserClipdService {
     name: String,
     window: Window,
     state: ServiceState,
-    formatter: ClipboardFormatter,
+    engine: ClipdEngine<WindowsClipboard>,
     retry_show_tray_icon: bool,
+    pause_on_start: bool,
 }
 
 impl UserClipdService {
@@ -91,11 +94,11 @@ impl UserClipdService {
         }
     }
 
-    fn new(name: &str) -> Result<UserClipdService> {
-        unsafe { Self::unsafe_new(name) }
+    fn new(name: &str, config: &Config) -> Result<UserClipdService> {
+        unsafe { Self::unsafe_new(name, config) }
     }
 
-    unsafe fn unsafe_new(name: &str) -> Result<UserClipdService> {
+    unsafe fn unsafe_new(name: &str, config: &Config) -> Result<UserClipdService> {
         let service_type;
         let service_pipe = Pipe::connect(PIPE, 0);
         if GetLastError() == ERROR_FILE_NOT_FOUND || GetLastError() == ERROR_SEM_TIMEOUT {
@@ -116,15 +119,20 @@ impl UserClipdService {
                 Some(wnd_proc),
             )
         };
-        let fmt = ClipboardFormatter::new(window.hwnd);
+        let mut engine = ClipdEngine::new(
+            WindowsClipboard::new(window.hwnd),
+            Formatters::clipboard(config.formatter()),
+        );
+        engine.set_debounce(config.selection.debounce);
 
         Ok(Self {
             service_type,
             name: name.to_owned(),
             window,
-            formatter: fmt,
+            engine,
             state: ServiceState::StartPending,
             retry_show_tray_icon: false,
+            pause_on_start: config.pause_on_start,
         })
     }
 
@@ -148,7 +156,11 @@ impl UserClipdService {
         }) {
             log::warn!("Set ctrlc handler failed: {:?}", e);
         }
-        self.state = ServiceState::Running;
+        // The daemon service reports its own state, only a user launched instance starts paused.
+        self.state = match self.service_type {
+            ServiceType::Normal { mutex: _ } if self.pause_on_start => ServiceState::Paused,
+            _ => ServiceState::Running,
+        };
         self.retry_show_tray_icon = self.show_tray_icon().is_err();
 
         match &mut self.service_type {
@@ -260,7 +272,7 @@ unsafe extern "system" fn wnd_proc(
             return DefWindowProcW(window, message, wparam, lparam);
         }
     };
-    let service = service.lock().unwrap();
+    let mut service = service.lock().unwrap();
 
     match message {
         WM_CREATE => {
@@ -268,7 +280,7 @@ unsafe extern "system" fn wnd_proc(
         }
         WM_DESTROY => {
             log::debug!("WM_DESTROY");
-            service.formatter.destroy();
+            service.engine.backend().destroy();
             service.delete_tray_icon();
             drop(service);
 
@@ -282,7 +294,7 @@ unsafe extern "system" fn wnd_proc(
                 return LRESULT(0);
             }
 
-            if let Err(e) = service.formatter.fmt() {
+            if let Err(e) = service.engine.on_change(Selection::Clipboard) {
                 log::error!("WM_CLIPBOARDUPDATE error: {:?}", e);
             }
             drop(service);
@@ -0,0 +1,30 @@
+use std::path::PathBuf;
+
+/// `$XDG_CONFIG_HOME`, `~/.config` when unset, `%APPDATA%` on Windows.
+pub fn config_home() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    return std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_os = "windows"))]
+    return xdg_dir("XDG_CONFIG_HOME").or_else(|| home().map(|h| h.join(".config")));
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn home() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+}
+
+/// The spec says relative paths are invalid and should be ignored.
+#[cfg(not(target_os = "windows"))]
+fn xdg_dir(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name)
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+}
+
+/// `$XDG_RUNTIME_DIR`, the temporary directory when unset.
+#[cfg(not(target_os = "windows"))]
+pub fn runtime_dir() -> PathBuf {
+    xdg_dir("XDG_RUNTIME_DIR").unwrap_or_else(std::env::temp_dir)
+}
@@ -1,5 +1,6 @@
 pub fn start_tracing(
     level: log::Level,
+    dir: &std::path::Path,
     filename: &str,
 ) -> Result<tracing_appender::non_blocking::WorkerGuard, time::error::IndeterminateOffset> {
     use time::{format_description, UtcOffset};
@@ -15,7 +16,7 @@ pub fn start_tracing(
     let env_filter =
         EnvFilter::try_from_env("RUST_LOG").unwrap_or_else(|_| EnvFilter::new(level.as_str()));
     let formatting_layer = fmt::layer().compact().with_writer(std::io::stdout);
-    let file_appender = rolling::never(std::env::temp_dir(), filename);
+    let file_appender = rolling::never(dir, filename);
     let local_time = OffsetTime::new(
         UtcOffset::current_local_offset()?,
         format_description::parse(